@@ -0,0 +1,181 @@
+use proc_macro2::{Ident, TokenStream};
+use quote::{format_ident, quote};
+use syn::{Attribute, Data, DeriveInput, Fields, Type};
+
+/// The optional `#[agent(request = ..)]` attribute on the enum.
+#[derive(Default)]
+struct EnumAttr {
+    request: Option<Type>,
+}
+
+fn parse_enum_attr(attrs: &[Attribute]) -> syn::Result<EnumAttr> {
+    let mut result = EnumAttr::default();
+    for attr in attrs {
+        if !attr.path().is_ident("agent") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("request") {
+                result.request = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `request`"))
+            }
+        })?;
+    }
+    Ok(result)
+}
+
+/// Returns true if the variant is marked `#[agent(no_outbox)]`.
+fn parse_variant_attr(attrs: &[Attribute]) -> syn::Result<bool> {
+    let mut no_outbox = false;
+    for attr in attrs {
+        if !attr.path().is_ident("agent") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("no_outbox") {
+                no_outbox = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `no_outbox`"))
+            }
+        })?;
+    }
+    Ok(no_outbox)
+}
+
+/// Converts a variant name like `FlockCreator` to `flock_creator`.
+fn snake_case(ident: &Ident) -> String {
+    let mut result = String::new();
+    for (i, c) in ident.to_string().chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                result.push('_');
+            }
+            result.extend(c.to_lowercase());
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+pub fn derive(input: DeriveInput) -> syn::Result<TokenStream> {
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "AgentEnum can only be derived for enums",
+            ))
+        }
+    };
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let attr = parse_enum_attr(&input.attrs)?;
+
+    let mut from_impls = vec![];
+    let mut methods = vec![];
+    let mut id_arms = vec![];
+    let mut outbox_arms = vec![];
+    for variant in &data.variants {
+        let ty = match &variant.fields {
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => &fields.unnamed[0].ty,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    variant,
+                    "each variant must hold exactly one agent state, e.g. `Boid(Boid)`",
+                ))
+            }
+        };
+        let ident = &variant.ident;
+        let snake = snake_case(ident);
+        let try_into = format_ident!("try_into_{}", snake);
+        let as_ref = format_ident!("as_{}", snake);
+        let as_mut = format_ident!("as_{}_mut", snake);
+
+        from_impls.push(quote! {
+            impl #impl_generics ::std::convert::From<#ty> for #name #ty_generics #where_clause {
+                fn from(v: #ty) -> Self {
+                    #name::#ident(v)
+                }
+            }
+        });
+
+        methods.push(quote! {
+            #vis fn #try_into(self) -> ::rust_agents::act_map_if::TryIntoResult<#ty, Self> {
+                match self {
+                    #name::#ident(v) => ::rust_agents::act_map_if::TryIntoResult::Ok(v),
+                    #[allow(unreachable_patterns)]
+                    _ => ::rust_agents::act_map_if::TryIntoResult::Failed(self),
+                }
+            }
+
+            #vis fn #as_ref(&self) -> ::std::option::Option<&#ty> {
+                match self {
+                    #name::#ident(v) => ::std::option::Option::Some(v),
+                    #[allow(unreachable_patterns)]
+                    _ => ::std::option::Option::None,
+                }
+            }
+
+            #vis fn #as_mut(&mut self) -> ::std::option::Option<&mut #ty> {
+                match self {
+                    #name::#ident(v) => ::std::option::Option::Some(v),
+                    #[allow(unreachable_patterns)]
+                    _ => ::std::option::Option::None,
+                }
+            }
+        });
+
+        id_arms.push(quote! {
+            #name::#ident(v) => ::rust_agents::utils::BaseOp::id(v),
+        });
+
+        if parse_variant_attr(&variant.attrs)? {
+            outbox_arms.push(quote! {
+                #name::#ident(_) => ::std::vec::Vec::new(),
+            });
+        } else if let Some(request) = &attr.request {
+            outbox_arms.push(quote! {
+                #name::#ident(v) => ::rust_agents::utils::AgentBase::<#request>::empty_system_outbox(v),
+            });
+        }
+    }
+
+    let agent_base = attr.request.map(|request| {
+        quote! {
+            impl #impl_generics ::rust_agents::utils::AgentBase<#request>
+                for #name #ty_generics #where_clause
+            {
+                fn empty_system_outbox(&mut self) -> ::std::vec::Vec<#request> {
+                    match self {
+                        #(#outbox_arms)*
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(quote! {
+        #(#from_impls)*
+
+        #[allow(dead_code)]
+        impl #impl_generics #name #ty_generics #where_clause {
+            #(#methods)*
+        }
+
+        impl #impl_generics ::rust_agents::utils::BaseOp for #name #ty_generics #where_clause {
+            fn id(&self) -> ::rust_agents::utils::AgentId {
+                match self {
+                    #(#id_arms)*
+                }
+            }
+        }
+
+        #agent_base
+    })
+}
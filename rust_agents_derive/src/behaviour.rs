@@ -0,0 +1,112 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{parse_quote, Data, DeriveInput, Fields, Type};
+
+/// The optional `#[behaviour(state = .., context = ..)]` attribute.
+#[derive(Default)]
+struct BehaviourAttr {
+    state: Option<Type>,
+    context: Option<Type>,
+}
+
+fn parse_attr(input: &DeriveInput) -> syn::Result<BehaviourAttr> {
+    let mut result = BehaviourAttr::default();
+    for attr in &input.attrs {
+        if !attr.path().is_ident("behaviour") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("state") {
+                result.state = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("context") {
+                result.context = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `state` or `context`"))
+            }
+        })?;
+    }
+    Ok(result)
+}
+
+pub fn derive(input: DeriveInput) -> syn::Result<TokenStream> {
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "Behaviour can only be derived for enums",
+            ))
+        }
+    };
+
+    let mut variants = vec![];
+    let mut field_types = vec![];
+    for variant in &data.variants {
+        match &variant.fields {
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                variants.push(&variant.ident);
+                field_types.push(&fields.unnamed[0].ty);
+            }
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    variant,
+                    "each variant must hold exactly one behaviour, e.g. `Alice(AliceBehaviour)`",
+                ))
+            }
+        }
+    }
+
+    let attr = parse_attr(&input)?;
+    let mut generics = input.generics.clone();
+    let state: Type = match attr.state {
+        Some(state) => state,
+        None => {
+            generics.params.push(parse_quote!(__STATE));
+            parse_quote!(__STATE)
+        }
+    };
+    let context: Type = match attr.context {
+        Some(context) => context,
+        None => {
+            generics.params.push(parse_quote!(__CONTEXT));
+            parse_quote!(__CONTEXT)
+        }
+    };
+    {
+        let where_clause = generics.make_where_clause();
+        for ty in &field_types {
+            where_clause.predicates.push(parse_quote!(
+                #ty: ::rust_agents::behaviour::Behaviour<#state, #context>
+            ));
+        }
+    }
+
+    let name = &input.ident;
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let (ext_impl_generics, ty_generics, ext_where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::rust_agents::behaviour::Behaviour<#state, #context>
+            for #name #ty_generics #where_clause
+        {
+            fn act(&self, state: &#state, context: &#context) -> #state {
+                match self {
+                    #(#name::#variants(behaviour) => behaviour.act(state, context),)*
+                }
+            }
+
+            fn act_changed(&self, state: &#state, context: &#context) -> Option<#state> {
+                match self {
+                    #(#name::#variants(behaviour) => behaviour.act_changed(state, context),)*
+                }
+            }
+        }
+
+        impl #ext_impl_generics ::rust_agents::behaviour_ext::BehaviourExt
+            for #name #ty_generics #ext_where_clause
+        {
+        }
+    })
+}
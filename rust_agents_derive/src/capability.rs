@@ -0,0 +1,134 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{Data, DeriveInput, Fields, GenericArgument, Member, PathArguments, Type};
+
+/// The field attributes understood by the capability derives.
+const FIELD_KEYS: &[&str] = &["id", "system_outbox", "color"];
+
+/// Finds the field marked `#[agent(key)]`, returning how to access it and
+/// its type.
+fn find_field<'a>(input: &'a DeriveInput, key: &str) -> syn::Result<(Member, &'a Type)> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "agent capability traits can only be derived for structs",
+            ))
+        }
+    };
+    let members: Vec<Member> = match fields {
+        Fields::Named(named) => named
+            .named
+            .iter()
+            .map(|f| Member::Named(f.ident.clone().unwrap()))
+            .collect(),
+        _ => (0..fields.len()).map(Member::from).collect(),
+    };
+
+    let mut found = None;
+    for (field, member) in fields.iter().zip(members) {
+        for attr in &field.attrs {
+            if !attr.path().is_ident("agent") {
+                continue;
+            }
+            attr.parse_nested_meta(|meta| {
+                if !FIELD_KEYS.iter().any(|k| meta.path.is_ident(k)) {
+                    return Err(meta.error(format!("expected one of {}", FIELD_KEYS.join(", "))));
+                }
+                if meta.path.is_ident(key) {
+                    if found.is_some() {
+                        return Err(meta.error(format!("duplicate #[agent({})] field", key)));
+                    }
+                    found = Some((member.clone(), &field.ty));
+                }
+                Ok(())
+            })?;
+        }
+    }
+    found.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &input.ident,
+            format!("expected a field marked #[agent({})]", key),
+        )
+    })
+}
+
+/// Gets `T` from a field of type `Vec<T>`.
+fn vec_element(ty: &Type) -> syn::Result<&Type> {
+    if let Type::Path(path) = ty {
+        if let Some(segment) = path.path.segments.last() {
+            if segment.ident == "Vec" {
+                if let PathArguments::AngleBracketed(args) = &segment.arguments {
+                    if let Some(GenericArgument::Type(element)) = args.args.first() {
+                        return Ok(element);
+                    }
+                }
+            }
+        }
+    }
+    Err(syn::Error::new_spanned(
+        ty,
+        "the #[agent(system_outbox)] field must be a Vec of requests",
+    ))
+}
+
+pub fn derive_base_op(input: DeriveInput) -> syn::Result<TokenStream> {
+    let (field, _) = find_field(&input, "id")?;
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rust_agents::utils::BaseOp for #name #ty_generics #where_clause {
+            fn id(&self) -> ::rust_agents::utils::AgentId {
+                self.#field
+            }
+        }
+    })
+}
+
+pub fn derive_system_op(input: DeriveInput) -> syn::Result<TokenStream> {
+    let (field, ty) = find_field(&input, "system_outbox")?;
+    let request = vec_element(ty)?;
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rust_agents::utils::SystemOp for #name #ty_generics #where_clause {
+            type RequestType = #request;
+            fn request(&mut self, request: #request) {
+                self.#field.push(request);
+            }
+        }
+    })
+}
+
+pub fn derive_agent_base(input: DeriveInput) -> syn::Result<TokenStream> {
+    let (field, ty) = find_field(&input, "system_outbox")?;
+    let request = vec_element(ty)?;
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rust_agents::utils::AgentBase<#request>
+            for #name #ty_generics #where_clause
+        {
+            fn empty_system_outbox(&mut self) -> ::std::vec::Vec<#request> {
+                self.#field.drain(..).collect()
+            }
+        }
+    })
+}
+
+pub fn derive_color_op(input: DeriveInput) -> syn::Result<TokenStream> {
+    let (field, _) = find_field(&input, "color")?;
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rust_agents::utils::ColorOp for #name #ty_generics #where_clause {
+            fn get_color(&self) -> &::rust_agents::utils::Color {
+                &self.#field
+            }
+            fn set_color(&mut self, color: ::rust_agents::utils::Color) {
+                self.#field = color;
+            }
+        }
+    })
+}
@@ -0,0 +1,101 @@
+//! Derive macros for `rust_agents`.
+//!
+//! These are re-exported from `rust_agents` when its `derive` feature is
+//! enabled (the default), next to the traits they implement, so
+//! `use rust_agents::behaviour::Behaviour;` brings in both the trait and
+//! the derive.
+extern crate proc_macro;
+
+mod agent_enum;
+mod behaviour;
+mod capability;
+
+use proc_macro::TokenStream;
+use syn::{parse_macro_input, DeriveInput};
+
+/// Derives `Behaviour` for an enum whose variants each hold a behaviour.
+///
+/// Each variant must be a tuple variant with a single field, and `act`
+/// forwards to the behaviour in that field.
+///
+/// By default the impl is generic over STATE and CONTEXT and requires every
+/// variant's behaviour to implement `Behaviour<STATE, CONTEXT>`, so it
+/// picks up the combined bounds of the variants. Use
+/// `#[behaviour(state = Type, context = Type)]` to implement it for a
+/// single STATE and/or CONTEXT instead.
+///
+/// It also implements the `BehaviourExt` marker, so the enum gets the
+/// fluent combinator methods.
+#[proc_macro_derive(Behaviour, attributes(behaviour))]
+pub fn derive_behaviour(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    behaviour::derive(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Derives the plumbing for an enum of agent states.
+///
+/// Each variant must be a tuple variant holding a single agent state,
+/// and this generates, for each variant `Foo(FooState)`:
+/// * `From<FooState>` for the enum,
+/// * `try_into_foo(self) -> TryIntoResult<FooState, Self>` for use with
+///   `act_map_if`,
+/// * `as_foo(&self) -> Option<&FooState>` and
+///   `as_foo_mut(&mut self) -> Option<&mut FooState>` for use with
+///   `act_map_ref` and `act_map_mut`.
+///
+/// It also implements `BaseOp` for the enum, forwarding `id()` to the
+/// variant (so every state must implement `BaseOp`).
+///
+/// With `#[agent(request = RequestType)]` on the enum it also implements
+/// `AgentBase<RequestType>`, forwarding `empty_system_outbox` to the
+/// variant. Mark variants whose state never makes requests with
+/// `#[agent(no_outbox)]` and they will return an empty outbox instead.
+#[proc_macro_derive(AgentEnum, attributes(agent))]
+pub fn derive_agent_enum(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    agent_enum::derive(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Derives `BaseOp` for a struct, returning the field marked `#[agent(id)]`.
+#[proc_macro_derive(BaseOp, attributes(agent))]
+pub fn derive_base_op(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    capability::derive_base_op(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Derives `SystemOp` for a struct, pushing requests onto the `Vec` field
+/// marked `#[agent(system_outbox)]`. The request type is the element
+/// type of that `Vec`.
+#[proc_macro_derive(SystemOp, attributes(agent))]
+pub fn derive_system_op(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    capability::derive_system_op(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Derives `AgentBase` for a struct, draining the `Vec` field marked
+/// `#[agent(system_outbox)]`. This is the other half of `SystemOp`.
+#[proc_macro_derive(AgentBase, attributes(agent))]
+pub fn derive_agent_base(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    capability::derive_agent_base(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Derives `ColorOp` for a struct, reading and writing the field marked
+/// `#[agent(color)]`.
+#[proc_macro_derive(ColorOp, attributes(agent))]
+pub fn derive_color_op(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    capability::derive_color_op(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
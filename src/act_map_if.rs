@@ -13,30 +13,115 @@
 /// Also the state is cloned unconditionally even when the state is
 /// not going to be applied.
 ///
-/// These could both be avoided by making F take a reference and return
-/// an Optional on which we then apply the child behaviour and finally
+/// `act_map_ref` avoids both of these by taking a reference and returning
+/// an Option on which we then apply the child behaviour and finally
 /// recombine into the original state.
 use crate::behaviour::Behaviour;
+use crate::behaviour_ext::BehaviourExt;
+use crate::behaviour_mut::BehaviourMut;
 
 pub enum TryIntoResult<OK, FAILED> {
     Ok(OK),
     Failed(FAILED),
 }
 
+/// How `ActMapIf` picks the child state out of a state, for both its
+/// `Behaviour` and its `BehaviourMut` impl, so the two can't disagree.
+///
+/// A closure `Fn(STATE) -> TryIntoResult<CHILD, STATE>` is a projection.
+/// Updating through it in place clones the state, takes the child out of
+/// the clone and converts it back, so use a `Lens` to keep a pipeline of
+/// `BehaviourMut`s clone-free.
+pub trait Projection<STATE> {
+    type Child;
+
+    /// Takes the child out of `state`, or hands `state` back if it has none.
+    fn get(&self, state: STATE) -> TryIntoResult<Self::Child, STATE>;
+
+    /// Applies `f` to the child in `state`, if it has one.
+    fn update<U>(&self, state: &mut STATE, f: U)
+    where
+        U: FnOnce(&mut Self::Child);
+}
+
+impl<F, CHILD, STATE> Projection<STATE> for F
+where
+    F: Fn(STATE) -> TryIntoResult<CHILD, STATE>,
+    STATE: Clone,
+    CHILD: Into<STATE>,
+{
+    type Child = CHILD;
+
+    fn get(&self, state: STATE) -> TryIntoResult<CHILD, STATE> {
+        self(state)
+    }
+
+    fn update<U>(&self, state: &mut STATE, f: U)
+    where
+        U: FnOnce(&mut CHILD),
+    {
+        if let TryIntoResult::Ok(mut child) = self(state.clone()) {
+            f(&mut child);
+            *state = child.into();
+        }
+    }
+}
+
+/// A projection made of a conversion into the child, as `act_map_if`
+/// takes, and an in-place view of it, as `act_map_mut` takes. Both must
+/// pick out the same child.
+///
+/// This is usually made with `lens`.
+pub struct Lens<G, M> {
+    get: G,
+    get_mut: M,
+}
+
+pub fn lens<G, M, CHILD, STATE>(get: G, get_mut: M) -> Lens<G, M>
+where
+    G: Fn(STATE) -> TryIntoResult<CHILD, STATE>,
+    M: Fn(&mut STATE) -> Option<&mut CHILD>,
+{
+    Lens { get, get_mut }
+}
+
+impl<G, M, CHILD, STATE> Projection<STATE> for Lens<G, M>
+where
+    G: Fn(STATE) -> TryIntoResult<CHILD, STATE>,
+    M: Fn(&mut STATE) -> Option<&mut CHILD>,
+{
+    type Child = CHILD;
+
+    fn get(&self, state: STATE) -> TryIntoResult<CHILD, STATE> {
+        (self.get)(state)
+    }
+
+    fn update<U>(&self, state: &mut STATE, f: U)
+    where
+        U: FnOnce(&mut CHILD),
+    {
+        if let Some(child) = (self.get_mut)(state) {
+            f(child);
+        }
+    }
+}
+
 pub struct ActMapIf<A, F> {
     a: A,
     f: F,
 }
 
-impl<A, F, CHILD_STATE, STATE, CONTEXT> Behaviour<STATE, CONTEXT> for ActMapIf<A, F>
+impl<A, F> BehaviourExt for ActMapIf<A, F> {}
+
+impl<A, F, STATE, CONTEXT> Behaviour<STATE, CONTEXT> for ActMapIf<A, F>
 where
     STATE: Clone,
-    F: Fn(STATE) -> TryIntoResult<CHILD_STATE, STATE>,
-    A: Behaviour<CHILD_STATE, CONTEXT>,
-    CHILD_STATE: Into<STATE>,
+    F: Projection<STATE>,
+    A: Behaviour<F::Child, CONTEXT>,
+    F::Child: Into<STATE>,
 {
     fn act(&self, state: &STATE, context: &CONTEXT) -> STATE {
-        let fs = (self.f)(state.clone());
+        let fs = self.f.get(state.clone());
         match fs {
             TryIntoResult::Ok(fs) => self.a.act(&fs, context).into(),
             TryIntoResult::Failed(state) => state,
@@ -44,6 +129,18 @@ where
     }
 }
 
+/// The in-place version updates the child through the same projection,
+/// which for a `Lens` means where it lives, without cloning.
+impl<A, F, STATE, CONTEXT> BehaviourMut<STATE, CONTEXT> for ActMapIf<A, F>
+where
+    F: Projection<STATE>,
+    A: BehaviourMut<F::Child, CONTEXT>,
+{
+    fn act_mut(&self, state: &mut STATE, context: &CONTEXT) {
+        self.f.update(state, |child| self.a.act_mut(child, context));
+    }
+}
+
 pub fn act_map_if<A, F>(f: F, a: A) -> ActMapIf<A, F> {
     ActMapIf { a, f }
 }
@@ -65,14 +162,14 @@ mod test {
         B(B),
     }
 
-    impl Into<AB> for A {
-        fn into(self) -> AB {
-            AB::A(self)
+    impl From<A> for AB {
+        fn from(a: A) -> AB {
+            AB::A(a)
         }
     }
 
     impl AB {
-        fn try_as_A(self) -> TryIntoResult<A, AB> {
+        fn try_as_a(self) -> TryIntoResult<A, AB> {
             match self {
                 AB::A(a) => TryIntoResult::Ok(a),
                 AB::B(_) => TryIntoResult::Failed(self),
@@ -94,7 +191,7 @@ mod test {
         // using an existing A only behaviour.
         let b = ActMapIf {
             a: IncrementABehaviour {},
-            f: |x: AB| x.try_as_A(),
+            f: |x: AB| x.try_as_a(),
         };
 
         let _m: &dyn Behaviour<AB, ()> = &b;
@@ -117,7 +214,7 @@ mod test {
         // Create a new behaviour that applied to the A variant of the AB enum
         let increment_a = IncrementABehaviour {};
         // using an existing A only behaviour.
-        let b = act_map_if(|x: AB| x.try_as_A(), increment_a);
+        let b = act_map_if(|x: AB| x.try_as_a(), increment_a);
 
         let _m: &dyn Behaviour<AB, ()> = &b;
 
@@ -133,4 +230,27 @@ mod test {
             assert_eq!(new_state, AB::B(B(7)));
         }
     }
+
+    struct IncrementAInPlace {}
+
+    impl BehaviourMut<A, ()> for IncrementAInPlace {
+        fn act_mut(&self, state: &mut A, _context: &()) {
+            state.0 += 1;
+        }
+    }
+
+    #[test]
+    fn test_act_map_if_mut() {
+        // The same projection serves the in-place path, by cloning the state.
+        let b = act_map_if(|x: AB| x.try_as_a(), IncrementAInPlace {});
+        let _m: &dyn BehaviourMut<AB, ()> = &b;
+
+        let mut state = AB::A(A(10));
+        b.act_mut(&mut state, &());
+        assert_eq!(state, AB::A(A(11)));
+
+        let mut state = AB::B(B(7));
+        b.act_mut(&mut state, &());
+        assert_eq!(state, AB::B(B(7)));
+    }
 }
@@ -0,0 +1,38 @@
+/// Implements a BehaviourMut that is only applied to the part of a state
+/// picked out by a mutable projection.
+///
+/// This is the in-place counterpart of `ActMapRef`: `project` returns
+/// `Some(&mut child)` if the child behaviour applies, and the child is
+/// updated where it lives. Nothing is cloned or rebuilt, and states that
+/// don't match are left alone.
+///
+/// This is usually accessed via `act_map_mut` not the
+/// `ActMapMut` struct directly.
+use crate::behaviour_ext::BehaviourExt;
+use crate::behaviour_mut::BehaviourMut;
+
+pub struct ActMapMut<A, P> {
+    a: A,
+    project: P,
+}
+
+impl<A, P> BehaviourExt for ActMapMut<A, P> {}
+
+impl<A, P, CHILD, STATE, CONTEXT> BehaviourMut<STATE, CONTEXT> for ActMapMut<A, P>
+where
+    P: Fn(&mut STATE) -> Option<&mut CHILD>,
+    A: BehaviourMut<CHILD, CONTEXT>,
+{
+    fn act_mut(&self, state: &mut STATE, context: &CONTEXT) {
+        if let Some(child) = (self.project)(state) {
+            self.a.act_mut(child, context);
+        }
+    }
+}
+
+pub fn act_map_mut<A, P, CHILD, STATE>(project: P, a: A) -> ActMapMut<A, P>
+where
+    P: Fn(&mut STATE) -> Option<&mut CHILD>,
+{
+    ActMapMut { a, project }
+}
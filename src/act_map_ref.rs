@@ -0,0 +1,155 @@
+/// Implements a Behaviour that is only applied to the part of a state
+/// picked out by a projection, without cloning the state to find out.
+///
+/// This is usually accessed via `act_map_ref` not the
+/// `ActMapRef` struct directly.
+///
+/// The projection (`preview`) borrows the parent state and returns
+/// `Some(&child)` if the child behaviour applies. The child behaviour
+/// then acts on that borrowed child, and `review` puts the new child back
+/// into (a copy of) the parent. For an enum parent `review` is usually just
+/// the variant constructor, for a struct parent it rebuilds the struct with
+/// the updated field.
+///
+/// When `preview` returns `None` the state is passed through untouched:
+/// `act_changed` returns `None` without cloning anything, and only the
+/// plain `act` (which must return an owned state) falls back to a clone.
+use crate::behaviour::Behaviour;
+use crate::behaviour_ext::BehaviourExt;
+
+pub struct ActMapRef<A, P, R> {
+    a: A,
+    preview: P,
+    review: R,
+}
+
+impl<A, P, R> BehaviourExt for ActMapRef<A, P, R> {}
+
+impl<A, P, R, CHILD, STATE, CONTEXT> Behaviour<STATE, CONTEXT> for ActMapRef<A, P, R>
+where
+    STATE: Clone,
+    P: Fn(&STATE) -> Option<&CHILD>,
+    R: Fn(&STATE, CHILD) -> STATE,
+    A: Behaviour<CHILD, CONTEXT>,
+{
+    fn act(&self, state: &STATE, context: &CONTEXT) -> STATE {
+        self.act_changed(state, context)
+            .unwrap_or_else(|| state.clone())
+    }
+
+    fn act_changed(&self, state: &STATE, context: &CONTEXT) -> Option<STATE> {
+        let child = (self.preview)(state)?;
+        let new_child = self.a.act_changed(child, context)?;
+        Some((self.review)(state, new_child))
+    }
+}
+
+pub fn act_map_ref<A, P, R, CHILD, STATE>(preview: P, review: R, a: A) -> ActMapRef<A, P, R>
+where
+    P: Fn(&STATE) -> Option<&CHILD>,
+    R: Fn(&STATE, CHILD) -> STATE,
+{
+    ActMapRef { a, preview, review }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[derive(Clone, PartialEq, Debug)]
+    struct A(u32);
+
+    #[derive(Clone, PartialEq, Debug)]
+    struct B(u32);
+
+    #[derive(PartialEq, Debug)]
+    enum AB {
+        A(A),
+        B(B),
+    }
+
+    /// Counts clones so we can check non-matching states are never copied.
+    static AB_CLONES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    impl Clone for AB {
+        fn clone(&self) -> Self {
+            AB_CLONES.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            match self {
+                AB::A(a) => AB::A(a.clone()),
+                AB::B(b) => AB::B(b.clone()),
+            }
+        }
+    }
+
+    impl AB {
+        fn as_a(&self) -> Option<&A> {
+            match self {
+                AB::A(a) => Some(a),
+                AB::B(_) => None,
+            }
+        }
+    }
+
+    #[derive(Clone, PartialEq, Debug)]
+    struct Wrapper {
+        label: String,
+        a: A,
+    }
+
+    struct IncrementABehaviour {}
+
+    impl Behaviour<A, ()> for IncrementABehaviour {
+        fn act(&self, state: &A, _context: &()) -> A {
+            A(state.0 + 1)
+        }
+    }
+
+    #[test]
+    fn test_act_map_ref_enum() {
+        let b = act_map_ref(AB::as_a, |_: &AB, a| AB::A(a), IncrementABehaviour {});
+
+        let _m: &dyn Behaviour<AB, ()> = &b;
+
+        {
+            let state = AB::A(A(10));
+            let new_state = b.act(&state, &());
+            assert_eq!(new_state, AB::A(A(11)));
+        }
+
+        {
+            let state = AB::B(B(7));
+            let clones_before = AB_CLONES.load(std::sync::atomic::Ordering::SeqCst);
+            assert_eq!(b.act_changed(&state, &()), None);
+            let clones_after = AB_CLONES.load(std::sync::atomic::Ordering::SeqCst);
+            assert_eq!(clones_before, clones_after);
+        }
+    }
+
+    #[test]
+    fn test_act_map_ref_struct() {
+        let b = act_map_ref(
+            |w: &Wrapper| Some(&w.a),
+            |w: &Wrapper, a| Wrapper {
+                label: w.label.clone(),
+                a,
+            },
+            IncrementABehaviour {},
+        );
+
+        let _m: &dyn Behaviour<Wrapper, ()> = &b;
+
+        let state = Wrapper {
+            label: "x".to_string(),
+            a: A(1),
+        };
+        let new_state = b.act(&state, &());
+        assert_eq!(
+            new_state,
+            Wrapper {
+                label: "x".to_string(),
+                a: A(2)
+            }
+        );
+    }
+}
@@ -1,3 +1,33 @@
 pub trait Behaviour<STATE, CONTEXT> {
     fn act(&self, state: &STATE, context: &CONTEXT) -> STATE;
+
+    /// Like `act`, but returns `None` when the behaviour leaves the state
+    /// untouched.
+    ///
+    /// The default just wraps `act`. Combinators that can tell a state
+    /// will not be changed (such as `ActMapRef` on a non-matching state)
+    /// override this so that the state is never cloned, and `step_agents`
+    /// uses it to leave such agents where they are.
+    fn act_changed(&self, state: &STATE, context: &CONTEXT) -> Option<STATE> {
+        Some(self.act(state, context))
+    }
 }
+
+/// Boxed behaviours (including `Box<dyn Behaviour<STATE, CONTEXT>>`) are
+/// behaviours too, so dynamically chosen behaviours can be used anywhere a
+/// static one can.
+impl<STATE, CONTEXT, B> Behaviour<STATE, CONTEXT> for Box<B>
+where
+    B: Behaviour<STATE, CONTEXT> + ?Sized,
+{
+    fn act(&self, state: &STATE, context: &CONTEXT) -> STATE {
+        (**self).act(state, context)
+    }
+
+    fn act_changed(&self, state: &STATE, context: &CONTEXT) -> Option<STATE> {
+        (**self).act_changed(state, context)
+    }
+}
+
+#[cfg(feature = "derive")]
+pub use rust_agents_derive::Behaviour;
@@ -0,0 +1,140 @@
+/// Fluent combinator methods for building up behaviours.
+///
+/// Rather than nesting constructors, e.g.
+///
+/// ```text
+/// Chain::chain(act_map_if(f, Chain::chain(a, b)), c)
+/// ```
+///
+/// we can write
+///
+/// ```text
+/// a.then(b).map_if(f).then(c)
+/// ```
+///
+/// Each method just builds the same static combinator types (`Chain`,
+/// `ActMapIf`, ...) so there is no dynamic dispatch involved.
+///
+/// Most behaviours implement `Behaviour<STATE, CONTEXT>` for many
+/// STATE/CONTEXT pairs, so the trait is not generic over them (that would
+/// need type annotations on every call), and so can't require `Behaviour`.
+/// Instead it is a marker: each of the library's behaviours implements it
+/// next to its definition, `#[derive(Behaviour)]` implements it too, and
+/// any other behaviour of your own opts in with an empty
+/// `impl BehaviourExt for MyBehaviour {}`. The combinator a method returns
+/// only implements `Behaviour` when its parts do.
+use crate::act_map_if::{act_map_if, ActMapIf, Projection};
+use crate::act_map_mut::{act_map_mut, ActMapMut};
+use crate::act_map_ref::{act_map_ref, ActMapRef};
+use crate::chain::Chain;
+use crate::when::When;
+use crate::with_context::WithContext;
+
+pub trait BehaviourExt: Sized {
+    /// Applies `self` and then `next` to the result.
+    fn then<B>(self, next: B) -> Chain<Self, B> {
+        Chain::chain(self, next)
+    }
+
+    /// Only applies `self` when `predicate` holds for the state and context.
+    fn when<P, STATE, CONTEXT>(self, predicate: P) -> When<Self, P>
+    where
+        P: Fn(&STATE, &CONTEXT) -> bool,
+    {
+        When::new(self, predicate)
+    }
+
+    /// Lifts `self` to act on a larger state, see `act_map_if`.
+    fn map_if<F, STATE>(self, f: F) -> ActMapIf<Self, F>
+    where
+        F: Projection<STATE>,
+    {
+        act_map_if(f, self)
+    }
+
+    /// Lifts `self` to act on a larger state without cloning it,
+    /// see `act_map_ref`.
+    fn map_ref<P, R, CHILD, STATE>(self, preview: P, review: R) -> ActMapRef<Self, P, R>
+    where
+        P: Fn(&STATE) -> Option<&CHILD>,
+        R: Fn(&STATE, CHILD) -> STATE,
+    {
+        act_map_ref(preview, review, self)
+    }
+
+    /// Lifts an in-place `self` to act on part of a larger state,
+    /// see `act_map_mut`.
+    fn map_mut<P, CHILD, STATE>(self, project: P) -> ActMapMut<Self, P>
+    where
+        P: Fn(&mut STATE) -> Option<&mut CHILD>,
+    {
+        act_map_mut(project, self)
+    }
+
+    /// Runs `self` in a larger context, using `f` to get the
+    /// context `self` expects.
+    fn with_context<F, CONTEXT, INNER>(self, f: F) -> WithContext<Self, F>
+    where
+        F: Fn(&CONTEXT) -> &INNER,
+    {
+        WithContext::new(self, f)
+    }
+}
+
+impl<B: ?Sized> BehaviourExt for Box<B> {}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::behaviour::Behaviour;
+
+    struct Increment {}
+
+    impl<CONTEXT> Behaviour<u32, CONTEXT> for Increment {
+        fn act(&self, state: &u32, _context: &CONTEXT) -> u32 {
+            state + 1
+        }
+    }
+
+    impl BehaviourExt for Increment {}
+
+    struct AddStep {}
+
+    impl BehaviourExt for AddStep {}
+
+    impl Behaviour<u32, u32> for AddStep {
+        fn act(&self, state: &u32, context: &u32) -> u32 {
+            state + context
+        }
+    }
+
+    struct Outer {
+        step: u32,
+        limit: u32,
+    }
+
+    #[test]
+    fn test_then_when() {
+        let b = Increment {}
+            .then(Increment {})
+            .when(|state: &u32, context: &Outer| *state < context.limit);
+
+        let context = Outer { step: 5, limit: 10 };
+        assert_eq!(b.act(&3, &context), 5);
+        assert_eq!(b.act(&12, &context), 12);
+        assert_eq!(b.act_changed(&12, &context), None);
+    }
+
+    #[test]
+    fn test_with_context() {
+        let b = AddStep {}
+            .with_context(|context: &Outer| &context.step)
+            .then(Increment {});
+
+        let _m: &dyn Behaviour<u32, Outer> = &b;
+
+        let context = Outer { step: 5, limit: 10 };
+        assert_eq!(b.act(&3, &context), 9);
+    }
+}
@@ -0,0 +1,145 @@
+/// An in-place companion to `Behaviour`.
+///
+/// A `Behaviour` reads `&STATE` and returns a new `STATE`, so each stage
+/// usually begins with a `state.clone()`. A `BehaviourMut` instead updates
+/// the state it is given, so a whole pipeline of them can share a single
+/// copy of the agent. `step_agents_mut` makes that one copy per agent,
+/// which keeps the other agents' view of the context at the previous
+/// time-step.
+///
+/// `functional` and `in_place` adapt between the two traits.
+use crate::behaviour::Behaviour;
+use crate::behaviour_ext::BehaviourExt;
+
+pub trait BehaviourMut<STATE, CONTEXT> {
+    fn act_mut(&self, state: &mut STATE, context: &CONTEXT);
+}
+
+/// Wraps a `BehaviourMut` so it can be used as a `Behaviour`, by cloning
+/// the state and updating the clone.
+pub struct Functional<B>(pub B);
+
+impl<B> BehaviourExt for Functional<B> {}
+
+pub fn functional<B>(b: B) -> Functional<B> {
+    Functional(b)
+}
+
+impl<B, STATE, CONTEXT> Behaviour<STATE, CONTEXT> for Functional<B>
+where
+    STATE: Clone,
+    B: BehaviourMut<STATE, CONTEXT>,
+{
+    fn act(&self, state: &STATE, context: &CONTEXT) -> STATE {
+        let mut state = state.clone();
+        self.0.act_mut(&mut state, context);
+        state
+    }
+}
+
+/// Wraps a `Behaviour` so it can be used as a `BehaviourMut`, by
+/// replacing the state with the one it returns.
+pub struct InPlace<B>(pub B);
+
+impl<B> BehaviourExt for InPlace<B> {}
+
+pub fn in_place<B>(b: B) -> InPlace<B> {
+    InPlace(b)
+}
+
+impl<B, STATE, CONTEXT> BehaviourMut<STATE, CONTEXT> for InPlace<B>
+where
+    B: Behaviour<STATE, CONTEXT>,
+{
+    fn act_mut(&self, state: &mut STATE, context: &CONTEXT) {
+        if let Some(new_state) = self.0.act_changed(state, context) {
+            *state = new_state;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::act_map_if::{act_map_if, lens, TryIntoResult};
+    use crate::act_map_mut::act_map_mut;
+    use crate::chain::Chain;
+
+    #[derive(Debug, PartialEq)]
+    enum AB {
+        A(u32),
+        B(u32),
+    }
+
+    impl AB {
+        fn try_as_a(self) -> TryIntoResult<u32, AB> {
+            match self {
+                AB::A(a) => TryIntoResult::Ok(a),
+                AB::B(_) => TryIntoResult::Failed(self),
+            }
+        }
+
+        fn as_a_mut(&mut self) -> Option<&mut u32> {
+            match self {
+                AB::A(a) => Some(a),
+                AB::B(_) => None,
+            }
+        }
+
+        fn as_b_mut(&mut self) -> Option<&mut u32> {
+            match self {
+                AB::B(b) => Some(b),
+                AB::A(_) => None,
+            }
+        }
+    }
+
+    impl From<u32> for AB {
+        fn from(a: u32) -> AB {
+            AB::A(a)
+        }
+    }
+
+    struct Increment {}
+
+    impl BehaviourMut<u32, ()> for Increment {
+        fn act_mut(&self, state: &mut u32, _context: &()) {
+            *state += 1;
+        }
+    }
+
+    struct Double {}
+
+    impl Behaviour<u32, ()> for Double {
+        fn act(&self, state: &u32, _context: &()) -> u32 {
+            state * 2
+        }
+    }
+
+    #[test]
+    fn test_adapters() {
+        let b = functional(Chain::chain(Increment {}, in_place(Double {})));
+        let _m: &dyn Behaviour<u32, ()> = &b;
+        assert_eq!(b.act(&3, &()), 8);
+    }
+
+    #[test]
+    fn test_in_place_pipeline() {
+        // AB is neither Clone nor Default, so this can only work if nothing
+        // in the pipeline clones or moves the state out.
+        let b = Chain::chain(
+            act_map_if(lens(AB::try_as_a, AB::as_a_mut), Increment {}),
+            act_map_mut(AB::as_b_mut, Chain::chain(Increment {}, Increment {})),
+        );
+        let _m: &dyn BehaviourMut<AB, ()> = &b;
+
+        let mut state = AB::A(1);
+        b.act_mut(&mut state, &());
+        assert_eq!(state, AB::A(2));
+
+        let mut state = AB::B(1);
+        b.act_mut(&mut state, &());
+        assert_eq!(state, AB::B(3));
+    }
+}
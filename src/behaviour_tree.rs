@@ -0,0 +1,732 @@
+/// Behaviour trees built on top of `Behaviour`.
+///
+/// Each node is ticked once per step and returns a `Status`. Leaves wrap
+/// ordinary behaviours and predicates:
+/// * `action(b)` applies `b` and succeeds.
+/// * `action_until(b, done)` applies `b` and is `Running` until `done`
+///   holds for the new state.
+/// * `condition(p)` succeeds or fails depending on `p`.
+///
+/// and these are combined with
+/// * `sequence((..))` - ticks children in order until one fails.
+/// * `selector((..))` - ticks children in order until one succeeds.
+/// * `inverter(n)` - swaps `Success` and `Failure`.
+/// * `repeat_until(n, status)` - ticks `n` once per step until it
+///   returns `status`.
+/// * `parallel((..), policy)` - ticks every child each step.
+///
+/// Children are given as tuples (of up to 12 nodes) and statically
+/// dispatched, in the same way as `sequence::Sequence`.
+///
+/// When a child of a sequence or selector returns `Running`, the next step
+/// resumes from that child rather than starting again. Behaviours are
+/// shared between agents, so this is remembered in the agent state, which
+/// must implement `BtMemoryOp`. Each tree is given an id when it is built
+/// with `BehaviourTree::new`, and gives each of its composite nodes an id
+/// within the tree, so several trees with different ids can keep their
+/// memory in the same agent. The ids come from the caller rather than a
+/// global counter, so the memory saved in an agent means the same thing
+/// from one run of a simulation to the next.
+///
+/// When a composite node finishes, the memory of its whole subtree is
+/// cleared. A branch that was left running when its parent finished, e.g.
+/// the slower children of a `parallel` that succeeded early, starts from
+/// the beginning the next time it is ticked.
+///
+/// `BehaviourTree` implements `Behaviour` and `BehaviourMut`, discarding
+/// the status of the root.
+use std::collections::BTreeMap;
+
+use crate::behaviour::Behaviour;
+use crate::behaviour_ext::BehaviourExt;
+use crate::behaviour_mut::BehaviourMut;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Status {
+    Success,
+    Failure,
+    Running,
+}
+
+/// The per-agent memory of which child each composite node is running.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BtMemory {
+    running: BTreeMap<NodeId, usize>,
+}
+
+impl BtMemory {
+    /// Forgets what every node in `subtree` was running.
+    fn forget(&mut self, subtree: Subtree) {
+        let end = NodeId {
+            tree: subtree.id.tree,
+            index: subtree.end,
+        };
+        let stale: Vec<NodeId> = self
+            .running
+            .range(subtree.id..end)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in stale {
+            self.running.remove(&id);
+        }
+    }
+
+    /// Forgets what every node of the tree with id `tree` was running,
+    /// e.g. when that tree is replaced by a different one with the same
+    /// id, or no longer used.
+    pub fn forget_tree(&mut self, tree: usize) {
+        self.running.retain(|id, _| id.tree != tree);
+    }
+}
+
+/// Identifies a composite node: the tree it belongs to, and its index in
+/// that tree.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NodeId {
+    tree: usize,
+    index: usize,
+}
+
+/// A composite node's id, and the end of the consecutive ids of the nodes
+/// below it.
+#[derive(Debug, Copy, Clone, Default)]
+struct Subtree {
+    id: NodeId,
+    end: usize,
+}
+
+/// Hands out the ids of one tree's nodes, in depth first order.
+pub struct NodeIds {
+    tree: usize,
+    next: usize,
+}
+
+impl NodeIds {
+    fn subtree<F>(&mut self, assign_children: F) -> Subtree
+    where
+        F: FnOnce(&mut NodeIds),
+    {
+        let id = NodeId {
+            tree: self.tree,
+            index: self.next,
+        };
+        self.next += 1;
+        assign_children(self);
+        Subtree { id, end: self.next }
+    }
+}
+
+pub trait BtMemoryOp {
+    fn bt_memory(&mut self) -> &mut BtMemory;
+}
+
+pub trait BtNode<STATE, CONTEXT> {
+    fn tick(&self, state: &mut STATE, context: &CONTEXT) -> Status;
+}
+
+/// Gives each node that keeps memory a unique id.
+/// This is called once by `BehaviourTree::new`.
+pub trait AssignIds {
+    fn assign_ids(&mut self, ids: &mut NodeIds);
+}
+
+/// A tuple of child nodes.
+pub trait BtChildren<STATE, CONTEXT> {
+    const COUNT: usize;
+    fn tick_child(&self, index: usize, state: &mut STATE, context: &CONTEXT) -> Status;
+}
+
+macro_rules! impl_children {
+    ($n:literal; $($name:ident $idx:tt),+) => {
+        impl<STATE, CONTEXT, $($name),+> BtChildren<STATE, CONTEXT> for ($($name,)+)
+        where
+            $($name: BtNode<STATE, CONTEXT>,)+
+        {
+            const COUNT: usize = $n;
+            fn tick_child(&self, index: usize, state: &mut STATE, context: &CONTEXT) -> Status {
+                match index {
+                    $($idx => self.$idx.tick(state, context),)+
+                    _ => panic!("behaviour tree child {} out of range", index),
+                }
+            }
+        }
+
+        impl<$($name),+> AssignIds for ($($name,)+)
+        where
+            $($name: AssignIds,)+
+        {
+            fn assign_ids(&mut self, ids: &mut NodeIds) {
+                $(self.$idx.assign_ids(ids);)+
+            }
+        }
+    };
+}
+
+impl_children!(1; A 0);
+impl_children!(2; A 0, B 1);
+impl_children!(3; A 0, B 1, C 2);
+impl_children!(4; A 0, B 1, C 2, D 3);
+impl_children!(5; A 0, B 1, C 2, D 3, E 4);
+impl_children!(6; A 0, B 1, C 2, D 3, E 4, F 5);
+impl_children!(7; A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_children!(8; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+impl_children!(9; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8);
+impl_children!(10; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9);
+impl_children!(11; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10);
+impl_children!(12; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11);
+
+/// The root of a behaviour tree.
+pub struct BehaviourTree<N> {
+    id: usize,
+    root: N,
+}
+
+impl<N> BehaviourExt for BehaviourTree<N> {}
+
+impl<N: AssignIds> BehaviourTree<N> {
+    /// Builds the tree with id `id`. Trees ticked on the same agent need
+    /// different ids.
+    pub fn new(id: usize, mut root: N) -> Self {
+        root.assign_ids(&mut NodeIds { tree: id, next: 0 });
+        BehaviourTree { id, root }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn tick<STATE, CONTEXT>(&self, state: &mut STATE, context: &CONTEXT) -> Status
+    where
+        N: BtNode<STATE, CONTEXT>,
+    {
+        self.root.tick(state, context)
+    }
+}
+
+impl<N, STATE, CONTEXT> BehaviourMut<STATE, CONTEXT> for BehaviourTree<N>
+where
+    N: BtNode<STATE, CONTEXT>,
+{
+    fn act_mut(&self, state: &mut STATE, context: &CONTEXT) {
+        self.root.tick(state, context);
+    }
+}
+
+impl<N, STATE, CONTEXT> Behaviour<STATE, CONTEXT> for BehaviourTree<N>
+where
+    STATE: Clone,
+    N: BtNode<STATE, CONTEXT>,
+{
+    fn act(&self, state: &STATE, context: &CONTEXT) -> STATE {
+        let mut state = state.clone();
+        self.root.tick(&mut state, context);
+        state
+    }
+}
+
+pub struct Action<B>(B);
+
+pub fn action<B>(b: B) -> Action<B> {
+    Action(b)
+}
+
+impl<B> AssignIds for Action<B> {
+    fn assign_ids(&mut self, _ids: &mut NodeIds) {}
+}
+
+impl<B, STATE, CONTEXT> BtNode<STATE, CONTEXT> for Action<B>
+where
+    B: Behaviour<STATE, CONTEXT>,
+{
+    fn tick(&self, state: &mut STATE, context: &CONTEXT) -> Status {
+        if let Some(new_state) = self.0.act_changed(state, context) {
+            *state = new_state;
+        }
+        Status::Success
+    }
+}
+
+pub struct ActionUntil<B, F> {
+    b: B,
+    done: F,
+}
+
+pub fn action_until<B, F, STATE, CONTEXT>(b: B, done: F) -> ActionUntil<B, F>
+where
+    F: Fn(&STATE, &CONTEXT) -> bool,
+{
+    ActionUntil { b, done }
+}
+
+impl<B, F> AssignIds for ActionUntil<B, F> {
+    fn assign_ids(&mut self, _ids: &mut NodeIds) {}
+}
+
+impl<B, F, STATE, CONTEXT> BtNode<STATE, CONTEXT> for ActionUntil<B, F>
+where
+    B: Behaviour<STATE, CONTEXT>,
+    F: Fn(&STATE, &CONTEXT) -> bool,
+{
+    fn tick(&self, state: &mut STATE, context: &CONTEXT) -> Status {
+        if let Some(new_state) = self.b.act_changed(state, context) {
+            *state = new_state;
+        }
+        if (self.done)(state, context) {
+            Status::Success
+        } else {
+            Status::Running
+        }
+    }
+}
+
+pub struct Condition<P>(P);
+
+pub fn condition<P, STATE, CONTEXT>(predicate: P) -> Condition<P>
+where
+    P: Fn(&STATE, &CONTEXT) -> bool,
+{
+    Condition(predicate)
+}
+
+impl<P> AssignIds for Condition<P> {
+    fn assign_ids(&mut self, _ids: &mut NodeIds) {}
+}
+
+impl<P, STATE, CONTEXT> BtNode<STATE, CONTEXT> for Condition<P>
+where
+    P: Fn(&STATE, &CONTEXT) -> bool,
+{
+    fn tick(&self, state: &mut STATE, context: &CONTEXT) -> Status {
+        if (self.0)(state, context) {
+            Status::Success
+        } else {
+            Status::Failure
+        }
+    }
+}
+
+/// Ticks the children in order, starting from the one left running last
+/// step (if any), until one returns `stop_on` or `Running`.
+/// Used by both `BtSequence` and `BtSelector`.
+fn tick_until<C, STATE, CONTEXT>(
+    subtree: Subtree,
+    children: &C,
+    stop_on: Status,
+    state: &mut STATE,
+    context: &CONTEXT,
+) -> Status
+where
+    C: BtChildren<STATE, CONTEXT>,
+    STATE: BtMemoryOp,
+{
+    let start = state.bt_memory().running.remove(&subtree.id).unwrap_or(0);
+    for index in start..C::COUNT {
+        match children.tick_child(index, state, context) {
+            Status::Running => {
+                state.bt_memory().running.insert(subtree.id, index);
+                return Status::Running;
+            }
+            status if status == stop_on => {
+                state.bt_memory().forget(subtree);
+                return status;
+            }
+            _ => {}
+        }
+    }
+    state.bt_memory().forget(subtree);
+    match stop_on {
+        Status::Failure => Status::Success,
+        _ => Status::Failure,
+    }
+}
+
+pub struct BtSequence<C> {
+    subtree: Subtree,
+    children: C,
+}
+
+pub fn sequence<C>(children: C) -> BtSequence<C> {
+    BtSequence {
+        subtree: Subtree::default(),
+        children,
+    }
+}
+
+impl<C: AssignIds> AssignIds for BtSequence<C> {
+    fn assign_ids(&mut self, ids: &mut NodeIds) {
+        let children = &mut self.children;
+        self.subtree = ids.subtree(|ids| children.assign_ids(ids));
+    }
+}
+
+impl<C, STATE, CONTEXT> BtNode<STATE, CONTEXT> for BtSequence<C>
+where
+    C: BtChildren<STATE, CONTEXT>,
+    STATE: BtMemoryOp,
+{
+    fn tick(&self, state: &mut STATE, context: &CONTEXT) -> Status {
+        tick_until(
+            self.subtree,
+            &self.children,
+            Status::Failure,
+            state,
+            context,
+        )
+    }
+}
+
+pub struct BtSelector<C> {
+    subtree: Subtree,
+    children: C,
+}
+
+pub fn selector<C>(children: C) -> BtSelector<C> {
+    BtSelector {
+        subtree: Subtree::default(),
+        children,
+    }
+}
+
+impl<C: AssignIds> AssignIds for BtSelector<C> {
+    fn assign_ids(&mut self, ids: &mut NodeIds) {
+        let children = &mut self.children;
+        self.subtree = ids.subtree(|ids| children.assign_ids(ids));
+    }
+}
+
+impl<C, STATE, CONTEXT> BtNode<STATE, CONTEXT> for BtSelector<C>
+where
+    C: BtChildren<STATE, CONTEXT>,
+    STATE: BtMemoryOp,
+{
+    fn tick(&self, state: &mut STATE, context: &CONTEXT) -> Status {
+        tick_until(
+            self.subtree,
+            &self.children,
+            Status::Success,
+            state,
+            context,
+        )
+    }
+}
+
+pub struct Inverter<N>(N);
+
+pub fn inverter<N>(node: N) -> Inverter<N> {
+    Inverter(node)
+}
+
+impl<N: AssignIds> AssignIds for Inverter<N> {
+    fn assign_ids(&mut self, ids: &mut NodeIds) {
+        self.0.assign_ids(ids);
+    }
+}
+
+impl<N, STATE, CONTEXT> BtNode<STATE, CONTEXT> for Inverter<N>
+where
+    N: BtNode<STATE, CONTEXT>,
+{
+    fn tick(&self, state: &mut STATE, context: &CONTEXT) -> Status {
+        match self.0.tick(state, context) {
+            Status::Success => Status::Failure,
+            Status::Failure => Status::Success,
+            Status::Running => Status::Running,
+        }
+    }
+}
+
+pub struct RepeatUntil<N> {
+    node: N,
+    until: Status,
+}
+
+/// Ticks `node` once per step, and is `Running` until `node` returns
+/// `until`, at which point it succeeds.
+pub fn repeat_until<N>(node: N, until: Status) -> RepeatUntil<N> {
+    RepeatUntil { node, until }
+}
+
+impl<N: AssignIds> AssignIds for RepeatUntil<N> {
+    fn assign_ids(&mut self, ids: &mut NodeIds) {
+        self.node.assign_ids(ids);
+    }
+}
+
+impl<N, STATE, CONTEXT> BtNode<STATE, CONTEXT> for RepeatUntil<N>
+where
+    N: BtNode<STATE, CONTEXT>,
+{
+    fn tick(&self, state: &mut STATE, context: &CONTEXT) -> Status {
+        if self.node.tick(state, context) == self.until {
+            Status::Success
+        } else {
+            Status::Running
+        }
+    }
+}
+
+/// When a `parallel` node succeeds or fails.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParallelPolicy {
+    /// Succeed once every child succeeds, fail as soon as one fails.
+    RequireAll,
+    /// Succeed as soon as one child succeeds, fail once every child fails.
+    RequireOne,
+}
+
+pub struct BtParallel<C> {
+    subtree: Subtree,
+    children: C,
+    policy: ParallelPolicy,
+}
+
+pub fn parallel<C>(children: C, policy: ParallelPolicy) -> BtParallel<C> {
+    BtParallel {
+        subtree: Subtree::default(),
+        children,
+        policy,
+    }
+}
+
+impl<C: AssignIds> AssignIds for BtParallel<C> {
+    fn assign_ids(&mut self, ids: &mut NodeIds) {
+        let children = &mut self.children;
+        self.subtree = ids.subtree(|ids| children.assign_ids(ids));
+    }
+}
+
+impl<C, STATE, CONTEXT> BtNode<STATE, CONTEXT> for BtParallel<C>
+where
+    C: BtChildren<STATE, CONTEXT>,
+    STATE: BtMemoryOp,
+{
+    fn tick(&self, state: &mut STATE, context: &CONTEXT) -> Status {
+        let mut successes = 0;
+        let mut failures = 0;
+        for index in 0..C::COUNT {
+            match self.children.tick_child(index, state, context) {
+                Status::Success => successes += 1,
+                Status::Failure => failures += 1,
+                Status::Running => {}
+            }
+        }
+        let status = match self.policy {
+            ParallelPolicy::RequireAll if failures > 0 => Status::Failure,
+            ParallelPolicy::RequireAll if successes == C::COUNT => Status::Success,
+            ParallelPolicy::RequireOne if successes > 0 => Status::Success,
+            ParallelPolicy::RequireOne if failures == C::COUNT => Status::Failure,
+            _ => Status::Running,
+        };
+        if status != Status::Running {
+            // Children still running are abandoned.
+            state.bt_memory().forget(self.subtree);
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::from_fn::from_fn;
+
+    #[derive(Debug, Clone, Default)]
+    struct Guard {
+        energy: u32,
+        walked: u32,
+        patrols: u32,
+        log: Vec<&'static str>,
+        memory: BtMemory,
+    }
+
+    impl BtMemoryOp for Guard {
+        fn bt_memory(&mut self) -> &mut BtMemory {
+            &mut self.memory
+        }
+    }
+
+    fn log(entry: &'static str) -> impl Fn(&Guard, &()) -> Guard {
+        move |state: &Guard, _: &()| {
+            let mut state = state.clone();
+            state.log.push(entry);
+            state
+        }
+    }
+
+    #[test]
+    fn test_running_sequence_resumes() {
+        // Walk for three steps, then log that we arrived.
+        let tree = BehaviourTree::new(
+            0,
+            sequence((
+                action(from_fn(log("start"))),
+                action_until(
+                    from_fn(|s: &Guard, _: &()| Guard {
+                        walked: s.walked + 1,
+                        ..s.clone()
+                    }),
+                    |s: &Guard, _: &()| s.walked >= 3,
+                ),
+                action(from_fn(log("arrived"))),
+            )),
+        );
+
+        let mut state = Guard::default();
+        assert_eq!(tree.tick(&mut state, &()), Status::Running);
+        assert_eq!(tree.tick(&mut state, &()), Status::Running);
+        assert_eq!(tree.tick(&mut state, &()), Status::Success);
+        // "start" only ran once, as the sequence resumed at the walk.
+        assert_eq!(state.log, vec!["start", "arrived"]);
+        assert_eq!(state.memory, BtMemory::default());
+    }
+
+    #[test]
+    fn test_selector_and_inverter() {
+        let tree = BehaviourTree::new(
+            0,
+            selector((
+                sequence((
+                    inverter(condition(|s: &Guard, _: &()| s.energy == 0)),
+                    action(from_fn(|s: &Guard, _: &()| Guard {
+                        energy: s.energy - 1,
+                        patrols: s.patrols + 1,
+                        ..s.clone()
+                    })),
+                )),
+                action(from_fn(log("rest"))),
+            )),
+        );
+
+        let _m: &dyn Behaviour<Guard, ()> = &tree;
+
+        let state = Guard {
+            energy: 1,
+            ..Guard::default()
+        };
+        let state = tree.act(&state, &());
+        assert_eq!((state.energy, state.patrols), (0, 1));
+        let state = tree.act(&state, &());
+        assert_eq!(state.log, vec!["rest"]);
+    }
+
+    #[test]
+    fn test_repeat_until_and_parallel() {
+        let tree = BehaviourTree::new(
+            0,
+            parallel(
+                (
+                    repeat_until(
+                        condition(|s: &Guard, _: &()| s.energy >= 2),
+                        Status::Success,
+                    ),
+                    action(from_fn(|s: &Guard, _: &()| Guard {
+                        energy: s.energy + 1,
+                        ..s.clone()
+                    })),
+                ),
+                ParallelPolicy::RequireAll,
+            ),
+        );
+
+        let mut state = Guard::default();
+        assert_eq!(tree.tick(&mut state, &()), Status::Running);
+        assert_eq!(tree.tick(&mut state, &()), Status::Running);
+        assert_eq!(tree.tick(&mut state, &()), Status::Success);
+        assert_eq!(state.energy, 3);
+    }
+
+    fn walk_until(walked: u32) -> impl BtNode<Guard, ()> + AssignIds {
+        action_until(
+            from_fn(|s: &Guard, _: &()| Guard {
+                walked: s.walked + 1,
+                ..s.clone()
+            }),
+            move |s: &Guard, _: &()| s.walked >= walked,
+        )
+    }
+
+    #[test]
+    fn test_trees_share_memory() {
+        let first = BehaviourTree::new(0, sequence((action(from_fn(log("first"))), walk_until(2))));
+        let second =
+            BehaviourTree::new(1, sequence((action(from_fn(log("second"))), walk_until(4))));
+
+        let mut state = Guard::default();
+        assert_eq!(first.tick(&mut state, &()), Status::Running);
+        // The second tree doesn't resume where the first one is running.
+        assert_eq!(second.tick(&mut state, &()), Status::Running);
+        assert_eq!(state.log, vec!["first", "second"]);
+        assert_eq!(state.memory.running.len(), 2);
+
+        // Dropping the second tree clears only its memory.
+        state.memory.forget_tree(second.id());
+        assert_eq!(state.memory.running.len(), 1);
+        assert_eq!(first.tick(&mut state, &()), Status::Success);
+        assert_eq!(state.log, vec!["first", "second"]);
+    }
+
+    #[test]
+    fn test_parallel_forgets_abandoned_children() {
+        let tree = BehaviourTree::new(
+            0,
+            parallel(
+                (
+                    sequence((action(from_fn(log("start"))), walk_until(10))),
+                    condition(|s: &Guard, _: &()| s.energy > 0),
+                ),
+                ParallelPolicy::RequireOne,
+            ),
+        );
+
+        let mut state = Guard {
+            energy: 1,
+            ..Guard::default()
+        };
+        assert_eq!(tree.tick(&mut state, &()), Status::Success);
+        assert_eq!(state.memory, BtMemory::default());
+        // The walk starts again rather than resuming.
+        tree.tick(&mut state, &());
+        assert_eq!(state.log, vec!["start", "start"]);
+    }
+
+    #[test]
+    fn test_selector_forgets_abandoned_branch() {
+        let tree = BehaviourTree::new(
+            0,
+            selector((
+                parallel(
+                    (
+                        condition(|s: &Guard, _: &()| s.energy > 0),
+                        sequence((action(from_fn(log("start"))), walk_until(10))),
+                    ),
+                    ParallelPolicy::RequireAll,
+                ),
+                action_until(
+                    from_fn(|s: &Guard, _: &()| Guard {
+                        energy: s.energy + 1,
+                        ..s.clone()
+                    }),
+                    |s: &Guard, _: &()| s.energy >= 2,
+                ),
+            )),
+        );
+
+        let mut state = Guard {
+            energy: 1,
+            ..Guard::default()
+        };
+        assert_eq!(tree.tick(&mut state, &()), Status::Running);
+        assert_eq!(state.memory.running.len(), 2);
+
+        // Out of energy, the selector switches to resting, and only
+        // remembers that.
+        state.energy = 0;
+        assert_eq!(tree.tick(&mut state, &()), Status::Running);
+        assert_eq!(state.memory.running.len(), 1);
+
+        assert_eq!(tree.tick(&mut state, &()), Status::Success);
+        tree.tick(&mut state, &());
+        assert_eq!(state.log, vec!["start", "start"]);
+    }
+}
@@ -4,13 +4,18 @@
 /// We don't use a Vec<Box<dyn Behaviour<STATE,CONTEXT>>> or similar as that
 /// would incur a dynamic dispatch / virtual call overhead.
 use crate::behaviour::Behaviour;
+use crate::behaviour_ext::BehaviourExt;
+use crate::behaviour_mut::BehaviourMut;
 
 pub struct Chain<A, B> {
     a: A,
     b: B,
 }
 
+impl<A, B> BehaviourExt for Chain<A, B> {}
+
 impl<A, B> Chain<A, B> {
+    #[allow(clippy::self_named_constructors)]
     pub fn chain(a: A, b: B) -> Self {
         Chain { a, b }
     }
@@ -25,4 +30,26 @@ where
         let temp_state = self.a.act(state, context);
         self.b.act(&temp_state, context)
     }
+
+    fn act_changed(&self, state: &STATE, context: &CONTEXT) -> Option<STATE> {
+        match self.a.act_changed(state, context) {
+            Some(temp_state) => Some(
+                self.b
+                    .act_changed(&temp_state, context)
+                    .unwrap_or(temp_state),
+            ),
+            None => self.b.act_changed(state, context),
+        }
+    }
+}
+
+impl<STATE, CONTEXT, A, B> BehaviourMut<STATE, CONTEXT> for Chain<A, B>
+where
+    A: BehaviourMut<STATE, CONTEXT>,
+    B: BehaviourMut<STATE, CONTEXT>,
+{
+    fn act_mut(&self, state: &mut STATE, context: &CONTEXT) {
+        self.a.act_mut(state, context);
+        self.b.act_mut(state, context);
+    }
 }
@@ -0,0 +1,419 @@
+/// Validation and conflict resolution for system requests.
+///
+/// `perform_checked_system_actions` is
+/// `utils::perform_ordered_system_actions` with a `RequestPolicy` in front
+/// of it. The policy sees each request in turn, in the `RequestOrder`
+/// given, along with the context as it was before any of them were
+/// applied, and accepts it, silently drops it or rejects it with a reason.
+/// Policies are told which requests were accepted, and keep what they need
+/// of them until the next step begins.
+///
+/// The library policies are
+/// * `first_wins(key)`, which rejects a request whose key matches the key
+///   of a request already accepted, e.g. a second removal of one agent,
+/// * `dedupe()`, which drops a request equal to one already accepted,
+/// * `validate(f)`, which rejects the requests `f` finds fault with.
+///
+/// A tuple of policies applies each in turn, and the first that doesn't
+/// accept a request decides it.
+///
+/// Rejected requests are returned, and handed back to the agents that
+/// made them through `RejectionsOp`, so they can react in the next step.
+use std::collections::{BTreeMap, HashSet};
+use std::hash::Hash;
+
+use crate::utils::{AgentBase, AgentId, AgentsMut, BaseOp, RequestOrder, System};
+
+/// A request along with the agent that made it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pending<REQUEST> {
+    pub from: AgentId,
+    pub request: REQUEST,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    /// Discard the request without telling the requester.
+    Drop,
+    Reject(String),
+}
+
+/// A request that was not applied, and why.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejected<REQUEST> {
+    pub from: AgentId,
+    pub request: REQUEST,
+    pub reason: String,
+}
+
+pub trait RequestPolicy<CONTEXT, REQUEST> {
+    /// Forgets the requests accepted in the previous step.
+    fn begin_step(&mut self) {}
+    fn check(&mut self, context: &CONTEXT, pending: &Pending<REQUEST>) -> Verdict;
+    /// Called with each request that every policy accepted.
+    fn accept(&mut self, _accepted: &Pending<REQUEST>) {}
+}
+
+/// Accepts every request.
+pub struct AcceptAll;
+
+impl<CONTEXT, REQUEST> RequestPolicy<CONTEXT, REQUEST> for AcceptAll {
+    fn check(&mut self, _: &CONTEXT, _: &Pending<REQUEST>) -> Verdict {
+        Verdict::Accept
+    }
+}
+
+pub struct FirstWins<F, K> {
+    key: F,
+    accepted: BTreeMap<K, AgentId>,
+}
+
+/// Rejects a request whose `key` matches that of a request accepted
+/// earlier in the step. Requests without a key never conflict.
+pub fn first_wins<F, K>(key: F) -> FirstWins<F, K> {
+    FirstWins {
+        key,
+        accepted: BTreeMap::new(),
+    }
+}
+
+impl<CONTEXT, REQUEST, F, K> RequestPolicy<CONTEXT, REQUEST> for FirstWins<F, K>
+where
+    F: Fn(&REQUEST) -> Option<K>,
+    K: Ord,
+{
+    fn begin_step(&mut self) {
+        self.accepted.clear();
+    }
+
+    fn check(&mut self, _context: &CONTEXT, pending: &Pending<REQUEST>) -> Verdict {
+        match (self.key)(&pending.request).and_then(|key| self.accepted.get(&key)) {
+            Some(earlier) => Verdict::Reject(format!(
+                "conflicts with an earlier request from {:?}",
+                earlier
+            )),
+            None => Verdict::Accept,
+        }
+    }
+
+    fn accept(&mut self, accepted: &Pending<REQUEST>) {
+        if let Some(key) = (self.key)(&accepted.request) {
+            self.accepted.entry(key).or_insert(accepted.from);
+        }
+    }
+}
+
+pub struct Dedupe<REQUEST> {
+    accepted: HashSet<REQUEST>,
+}
+
+/// Drops a request equal to one accepted earlier in the step, whoever
+/// made it.
+pub fn dedupe<REQUEST>() -> Dedupe<REQUEST> {
+    Dedupe {
+        accepted: HashSet::new(),
+    }
+}
+
+impl<CONTEXT, REQUEST> RequestPolicy<CONTEXT, REQUEST> for Dedupe<REQUEST>
+where
+    REQUEST: Hash + Eq + Clone,
+{
+    fn begin_step(&mut self) {
+        self.accepted.clear();
+    }
+
+    fn check(&mut self, _context: &CONTEXT, pending: &Pending<REQUEST>) -> Verdict {
+        if self.accepted.contains(&pending.request) {
+            Verdict::Drop
+        } else {
+            Verdict::Accept
+        }
+    }
+
+    fn accept(&mut self, accepted: &Pending<REQUEST>) {
+        self.accepted.insert(accepted.request.clone());
+    }
+}
+
+pub struct Validate<F> {
+    f: F,
+}
+
+/// Rejects the requests for which `f` returns an error, with the error as
+/// the reason.
+pub fn validate<F>(f: F) -> Validate<F> {
+    Validate { f }
+}
+
+impl<CONTEXT, REQUEST, F> RequestPolicy<CONTEXT, REQUEST> for Validate<F>
+where
+    F: Fn(&CONTEXT, &Pending<REQUEST>) -> Result<(), String>,
+{
+    fn check(&mut self, context: &CONTEXT, pending: &Pending<REQUEST>) -> Verdict {
+        match (self.f)(context, pending) {
+            Ok(()) => Verdict::Accept,
+            Err(reason) => Verdict::Reject(reason),
+        }
+    }
+}
+
+macro_rules! impl_policies {
+    ($($p:ident $idx:tt),+) => {
+        impl<CONTEXT, REQUEST, $($p),+> RequestPolicy<CONTEXT, REQUEST> for ($($p,)+)
+        where
+            $($p: RequestPolicy<CONTEXT, REQUEST>,)+
+        {
+            fn begin_step(&mut self) {
+                $(self.$idx.begin_step();)+
+            }
+
+            fn check(&mut self, context: &CONTEXT, pending: &Pending<REQUEST>) -> Verdict {
+                $(
+                    match self.$idx.check(context, pending) {
+                        Verdict::Accept => {}
+                        verdict => return verdict,
+                    }
+                )+
+                Verdict::Accept
+            }
+
+            fn accept(&mut self, accepted: &Pending<REQUEST>) {
+                $(self.$idx.accept(accepted);)+
+            }
+        }
+    };
+}
+
+impl_policies!(P0 0);
+impl_policies!(P0 0, P1 1);
+impl_policies!(P0 0, P1 1, P2 2);
+impl_policies!(P0 0, P1 1, P2 2, P3 3);
+impl_policies!(P0 0, P1 1, P2 2, P3 3, P4 4);
+impl_policies!(P0 0, P1 1, P2 2, P3 3, P4 4, P5 5);
+impl_policies!(P0 0, P1 1, P2 2, P3 3, P4 4, P5 5, P6 6);
+impl_policies!(P0 0, P1 1, P2 2, P3 3, P4 4, P5 5, P6 6, P7 7);
+impl_policies!(P0 0, P1 1, P2 2, P3 3, P4 4, P5 5, P6 6, P7 7, P8 8);
+impl_policies!(P0 0, P1 1, P2 2, P3 3, P4 4, P5 5, P6 6, P7 7, P8 8, P9 9);
+impl_policies!(P0 0, P1 1, P2 2, P3 3, P4 4, P5 5, P6 6, P7 7, P8 8, P9 9, P10 10);
+impl_policies!(P0 0, P1 1, P2 2, P3 3, P4 4, P5 5, P6 6, P7 7, P8 8, P9 9, P10 10, P11 11);
+
+/// Agents that are told which of their requests were rejected.
+///
+/// The rejections are replaced each step, so they only hold those from the
+/// previous step.
+pub trait RejectionsOp<REQUEST> {
+    fn rejections(&self) -> &[Rejected<REQUEST>];
+    fn rejections_mut(&mut self) -> &mut Vec<Rejected<REQUEST>>;
+}
+
+/// Gathers the agents' system requests, sorts them into `order`, passes
+/// them through `policy`, and applies the ones it accepts.
+///
+/// The rejected requests are given to the agents that made them, if they
+/// still exist, and returned. The agents' rejections from the previous
+/// step are cleared as their requests are gathered.
+pub fn perform_checked_system_actions<T, REQUEST, O, P>(
+    context: &mut T,
+    order: &O,
+    policy: &mut P,
+) -> Vec<Rejected<REQUEST>>
+where
+    T: System<REQUEST> + AgentsMut<<T as System<REQUEST>>::AgentType>,
+    T::AgentType: AgentBase<REQUEST> + BaseOp + RejectionsOp<REQUEST>,
+    O: RequestOrder<REQUEST>,
+    P: RequestPolicy<T, REQUEST>,
+    REQUEST: Clone,
+{
+    let mut pending = vec![];
+    context.for_each_agent_mut(|agent| {
+        agent.rejections_mut().clear();
+        let from = agent.id();
+        for request in agent.empty_system_outbox() {
+            pending.push(Pending { from, request });
+        }
+    });
+    if order.is_ordered() {
+        pending.sort_by_cached_key(|p| order.key(&p.request));
+    }
+
+    policy.begin_step();
+    let mut accepted = vec![];
+    let mut rejected = vec![];
+    for request in pending {
+        match policy.check(context, &request) {
+            Verdict::Accept => {
+                policy.accept(&request);
+                accepted.push(request);
+            }
+            Verdict::Drop => {}
+            Verdict::Reject(reason) => rejected.push(Rejected {
+                from: request.from,
+                request: request.request,
+                reason,
+            }),
+        }
+    }
+
+    context.apply_system_requests(accepted.into_iter().map(|p| p.request).collect());
+
+    let mut by_agent: BTreeMap<AgentId, Vec<Rejected<REQUEST>>> = BTreeMap::new();
+    for rejection in &rejected {
+        by_agent
+            .entry(rejection.from)
+            .or_default()
+            .push(rejection.clone());
+    }
+    for (id, rejections) in by_agent {
+        if let Some(agent) = context.agent_mut(id) {
+            *agent.rejections_mut() = rejections;
+        }
+    }
+    rejected
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::utils::Unordered;
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum Request {
+        Remove(AgentId),
+        Paint(AgentId, &'static str),
+    }
+
+    #[derive(Debug, Clone)]
+    struct Agent {
+        id: AgentId,
+        colour: &'static str,
+        outbox: Vec<Request>,
+        rejections: Vec<Rejected<Request>>,
+    }
+
+    impl Agent {
+        fn new(id: u64, outbox: Vec<Request>) -> Self {
+            Agent {
+                id: AgentId(id),
+                colour: "black",
+                outbox,
+                rejections: vec![],
+            }
+        }
+    }
+
+    impl BaseOp for Agent {
+        fn id(&self) -> AgentId {
+            self.id
+        }
+    }
+
+    impl AgentBase<Request> for Agent {
+        fn empty_system_outbox(&mut self) -> Vec<Request> {
+            self.outbox.drain(..).collect()
+        }
+    }
+
+    impl RejectionsOp<Request> for Agent {
+        fn rejections(&self) -> &[Rejected<Request>] {
+            &self.rejections
+        }
+        fn rejections_mut(&mut self) -> &mut Vec<Rejected<Request>> {
+            &mut self.rejections
+        }
+    }
+
+    struct Context {
+        agents: BTreeMap<AgentId, Agent>,
+    }
+
+    impl System<Request> for Context {
+        type AgentType = Agent;
+        fn apply_system_request(&mut self, request: Request) {
+            match request {
+                Request::Remove(id) => {
+                    self.agents.remove(&id).unwrap();
+                }
+                Request::Paint(id, colour) => self.agents.get_mut(&id).unwrap().colour = colour,
+            }
+        }
+    }
+
+    impl AgentsMut<Agent> for Context {
+        fn for_each_agent_mut<F>(&mut self, f: F)
+        where
+            F: FnMut(&mut Agent),
+        {
+            self.agents.values_mut().for_each(f)
+        }
+
+        fn agent_mut(&mut self, id: AgentId) -> Option<&mut Agent> {
+            self.agents.get_mut(&id)
+        }
+    }
+
+    #[test]
+    fn test_checked_system_actions() {
+        use self::Request::*;
+        let agents = vec![
+            Agent::new(1, vec![Remove(AgentId(3)), Paint(AgentId(2), "red")]),
+            Agent::new(2, vec![Remove(AgentId(3)), Remove(AgentId(9))]),
+            Agent::new(3, vec![Paint(AgentId(2), "red")]),
+        ];
+        let mut context = Context {
+            agents: agents.into_iter().map(|a| (a.id, a)).collect(),
+        };
+
+        let mut policy = (
+            validate(
+                |context: &Context, pending: &Pending<Request>| match pending.request {
+                    Remove(id) | Paint(id, _) if !context.agents.contains_key(&id) => {
+                        Err(format!("no agent {:?}", id))
+                    }
+                    _ => Ok(()),
+                },
+            ),
+            first_wins(|request: &Request| match request {
+                Remove(id) => Some(*id),
+                Paint(..) => None,
+            }),
+            // Agent 3's paint request is the same as agent 1's.
+            dedupe(),
+        );
+        let rejected = perform_checked_system_actions(&mut context, &Unordered, &mut policy);
+
+        assert_eq!(
+            rejected,
+            vec![
+                Rejected {
+                    from: AgentId(2),
+                    request: Remove(AgentId(3)),
+                    reason: "conflicts with an earlier request from AgentId(1)".to_string(),
+                },
+                Rejected {
+                    from: AgentId(2),
+                    request: Remove(AgentId(9)),
+                    reason: "no agent AgentId(9)".to_string(),
+                },
+            ]
+        );
+        assert_eq!(context.agents.len(), 2);
+        assert_eq!(context.agents[&AgentId(2)].colour, "red");
+        assert!(context.agents[&AgentId(1)].rejections().is_empty());
+        assert_eq!(context.agents[&AgentId(2)].rejections(), &rejected[..]);
+
+        // Rejections only last a step.
+        perform_checked_system_actions(&mut context, &Unordered, &mut AcceptAll);
+        assert!(context.agents[&AgentId(2)].rejections().is_empty());
+
+        // Neither do the keys the policy remembers.
+        context.agents.get_mut(&AgentId(1)).unwrap().outbox = vec![Remove(AgentId(2))];
+        let rejected = perform_checked_system_actions(&mut context, &Unordered, &mut policy);
+        assert!(rejected.is_empty());
+        assert_eq!(context.agents.len(), 1);
+    }
+}
@@ -0,0 +1,96 @@
+/// Turns a closure into a behaviour.
+///
+/// Small stages, like decrementing a counter, don't need their own named
+/// struct:
+///
+/// ```text
+/// let countdown = from_fn(|state: &Timer, _context: &Context| Timer {
+///     remaining: state.remaining - 1,
+///     ..state.clone()
+/// });
+/// ```
+///
+/// `from_fn` wraps a `Fn(&STATE, &CONTEXT) -> STATE` as a `Behaviour`, and
+/// `from_fn_mut` wraps a `Fn(&mut STATE, &CONTEXT)` as a `BehaviourMut`.
+use crate::behaviour::Behaviour;
+use crate::behaviour_ext::BehaviourExt;
+use crate::behaviour_mut::BehaviourMut;
+
+pub struct FromFn<F>(F);
+
+impl<F> BehaviourExt for FromFn<F> {}
+
+pub fn from_fn<F, STATE, CONTEXT>(f: F) -> FromFn<F>
+where
+    F: Fn(&STATE, &CONTEXT) -> STATE,
+{
+    FromFn(f)
+}
+
+impl<F, STATE, CONTEXT> Behaviour<STATE, CONTEXT> for FromFn<F>
+where
+    F: Fn(&STATE, &CONTEXT) -> STATE,
+{
+    fn act(&self, state: &STATE, context: &CONTEXT) -> STATE {
+        (self.0)(state, context)
+    }
+}
+
+pub struct FromFnMut<F>(F);
+
+impl<F> BehaviourExt for FromFnMut<F> {}
+
+pub fn from_fn_mut<F, STATE, CONTEXT>(f: F) -> FromFnMut<F>
+where
+    F: Fn(&mut STATE, &CONTEXT),
+{
+    FromFnMut(f)
+}
+
+impl<F, STATE, CONTEXT> BehaviourMut<STATE, CONTEXT> for FromFnMut<F>
+where
+    F: Fn(&mut STATE, &CONTEXT),
+{
+    fn act_mut(&self, state: &mut STATE, context: &CONTEXT) {
+        (self.0)(state, context)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::with_context::with_mapped_context;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Timer {
+        remaining: u32,
+    }
+
+    struct Outer {
+        rate: u32,
+    }
+
+    #[test]
+    fn test_from_fn() {
+        let countdown = from_fn(|state: &Timer, _context: &()| Timer {
+            remaining: state.remaining - 1,
+        });
+        let _m: &dyn Behaviour<Timer, ()> = &countdown;
+        assert_eq!(
+            countdown.act(&Timer { remaining: 3 }, &()),
+            Timer { remaining: 2 }
+        );
+    }
+
+    #[test]
+    fn test_from_fn_mut_in_mapped_context() {
+        let countdown = from_fn_mut(|state: &mut Timer, rate: &u32| state.remaining -= rate);
+        let b = with_mapped_context(|context: &Outer| &context.rate, countdown);
+        let _m: &dyn BehaviourMut<Timer, Outer> = &b;
+
+        let mut state = Timer { remaining: 10 };
+        b.act_mut(&mut state, &Outer { rate: 3 });
+        assert_eq!(state, Timer { remaining: 7 });
+    }
+}
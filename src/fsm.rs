@@ -0,0 +1,240 @@
+/// A finite-state-machine behaviour.
+///
+/// Each FSM node maps to a behaviour, and guarded transitions move the
+/// agent between nodes. The current node is stored in the agent state,
+/// which must implement `FsmNodeOp`.
+///
+/// Each step the FSM
+/// 1. checks the transitions out of the current node, in the order they
+///    were added, and takes the first whose guard holds for the state and
+///    context. Taking it runs the exit action of the old node, updates the
+///    node stored in the state, and runs the entry action of the new node.
+/// 2. applies the behaviour of the (possibly new) current node.
+///
+/// So an agent that transitions acts as the new node in the same step.
+/// At most one transition is taken per step. Nodes without a behaviour
+/// leave the state unchanged apart from any entry/exit actions.
+///
+/// All nodes share one behaviour type `B`, which keeps the dispatch static.
+/// To use different behaviours for different nodes wrap them in an enum
+/// (see `#[derive(Behaviour)]`).
+///
+/// Entry and exit actions get `&mut STATE`, so they can set flags and
+/// colours or issue `SystemOp` requests (such as removing the agent).
+///
+/// Guards and actions, unlike the node behaviours, are boxed closures.
+/// Every one is usually a different closure type, and a machine has any
+/// number of them, so keeping them static would make the type of the
+/// `Fsm` grow with each `transition` or `on_entry` call. Boxing them
+/// keeps the builder a plain list, at the cost of a virtual call per
+/// guard checked and action run.
+use crate::behaviour::Behaviour;
+use crate::behaviour_ext::BehaviourExt;
+
+pub trait FsmNodeOp {
+    type Node;
+    fn fsm_node(&self) -> Self::Node;
+    fn set_fsm_node(&mut self, node: Self::Node);
+}
+
+type Guard<STATE, CONTEXT> = Box<dyn Fn(&STATE, &CONTEXT) -> bool>;
+type Action<STATE, CONTEXT> = Box<dyn Fn(&mut STATE, &CONTEXT)>;
+
+struct Transition<NODE, STATE, CONTEXT> {
+    from: NODE,
+    to: NODE,
+    guard: Guard<STATE, CONTEXT>,
+}
+
+pub struct Fsm<NODE, B, STATE, CONTEXT> {
+    behaviours: Vec<(NODE, B)>,
+    transitions: Vec<Transition<NODE, STATE, CONTEXT>>,
+    on_entry: Vec<(NODE, Action<STATE, CONTEXT>)>,
+    on_exit: Vec<(NODE, Action<STATE, CONTEXT>)>,
+}
+
+impl<NODE, B, STATE, CONTEXT> BehaviourExt for Fsm<NODE, B, STATE, CONTEXT> {}
+
+impl<NODE, B, STATE, CONTEXT> Default for Fsm<NODE, B, STATE, CONTEXT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<NODE, B, STATE, CONTEXT> Fsm<NODE, B, STATE, CONTEXT> {
+    pub fn new() -> Self {
+        Fsm {
+            behaviours: vec![],
+            transitions: vec![],
+            on_entry: vec![],
+            on_exit: vec![],
+        }
+    }
+
+    /// Sets the behaviour applied while the agent is in `node`.
+    pub fn node(mut self, node: NODE, behaviour: B) -> Self {
+        self.behaviours.push((node, behaviour));
+        self
+    }
+
+    /// Adds a transition from `from` to `to`, taken when `guard` holds.
+    pub fn transition<G>(mut self, from: NODE, to: NODE, guard: G) -> Self
+    where
+        G: Fn(&STATE, &CONTEXT) -> bool + 'static,
+    {
+        self.transitions.push(Transition {
+            from,
+            to,
+            guard: Box::new(guard),
+        });
+        self
+    }
+
+    /// Adds an action run when the agent enters `node`.
+    pub fn on_entry<F>(mut self, node: NODE, action: F) -> Self
+    where
+        F: Fn(&mut STATE, &CONTEXT) + 'static,
+    {
+        self.on_entry.push((node, Box::new(action)));
+        self
+    }
+
+    /// Adds an action run when the agent leaves `node`.
+    pub fn on_exit<F>(mut self, node: NODE, action: F) -> Self
+    where
+        F: Fn(&mut STATE, &CONTEXT) + 'static,
+    {
+        self.on_exit.push((node, Box::new(action)));
+        self
+    }
+}
+
+impl<NODE, B, STATE, CONTEXT> Behaviour<STATE, CONTEXT> for Fsm<NODE, B, STATE, CONTEXT>
+where
+    NODE: Copy + PartialEq,
+    STATE: FsmNodeOp<Node = NODE> + Clone,
+    B: Behaviour<STATE, CONTEXT>,
+{
+    fn act(&self, state: &STATE, context: &CONTEXT) -> STATE {
+        self.act_changed(state, context)
+            .unwrap_or_else(|| state.clone())
+    }
+
+    fn act_changed(&self, state: &STATE, context: &CONTEXT) -> Option<STATE> {
+        let node = state.fsm_node();
+        let transition = self
+            .transitions
+            .iter()
+            .find(|t| t.from == node && (t.guard)(state, context));
+
+        let moved = transition.map(|t| {
+            let mut state = state.clone();
+            for (_, action) in self.on_exit.iter().filter(|(n, _)| *n == t.from) {
+                action(&mut state, context);
+            }
+            state.set_fsm_node(t.to);
+            for (_, action) in self.on_entry.iter().filter(|(n, _)| *n == t.to) {
+                action(&mut state, context);
+            }
+            state
+        });
+
+        let current = moved.as_ref().unwrap_or(state);
+        let behaviour = self
+            .behaviours
+            .iter()
+            .find(|(n, _)| *n == current.fsm_node())
+            .map(|(_, b)| b);
+        match behaviour {
+            Some(behaviour) => behaviour.act_changed(current, context).or(moved),
+            None => moved,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::utils::SystemOp;
+
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    enum Mood {
+        Waiting,
+        Greeted,
+        Leaving,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Person {
+        mood: Mood,
+        greetings: u32,
+        replies: u32,
+        requests: Vec<&'static str>,
+    }
+
+    impl FsmNodeOp for Person {
+        type Node = Mood;
+        fn fsm_node(&self) -> Mood {
+            self.mood
+        }
+        fn set_fsm_node(&mut self, node: Mood) {
+            self.mood = node;
+        }
+    }
+
+    impl SystemOp for Person {
+        type RequestType = &'static str;
+        fn request(&mut self, request: &'static str) {
+            self.requests.push(request);
+        }
+    }
+
+    struct Reply {}
+
+    impl Behaviour<Person, ()> for Reply {
+        fn act(&self, state: &Person, _context: &()) -> Person {
+            let mut state = state.clone();
+            state.replies += 1;
+            state
+        }
+    }
+
+    #[test]
+    fn test_fsm() {
+        let fsm = Fsm::new()
+            .node(Mood::Greeted, Reply {})
+            .transition(Mood::Waiting, Mood::Greeted, |s: &Person, _: &()| {
+                s.greetings > 0
+            })
+            .transition(Mood::Greeted, Mood::Leaving, |_: &Person, _: &()| true)
+            .on_entry(Mood::Leaving, |s: &mut Person, _: &()| {
+                s.request("remove me")
+            });
+
+        let _m: &dyn Behaviour<Person, ()> = &fsm;
+
+        let mut state = Person {
+            mood: Mood::Waiting,
+            greetings: 0,
+            replies: 0,
+            requests: vec![],
+        };
+
+        // No greeting yet so nothing happens.
+        assert_eq!(fsm.act_changed(&state, &()), None);
+
+        // Greeted: we move to the Greeted node and reply in the same step.
+        state.greetings = 1;
+        let state = fsm.act(&state, &());
+        assert_eq!(state.mood, Mood::Greeted);
+        assert_eq!(state.replies, 1);
+        assert!(state.requests.is_empty());
+
+        // Then we leave, which requests removal.
+        let state = fsm.act(&state, &());
+        assert_eq!(state.mood, Mood::Leaving);
+        assert_eq!(state.replies, 1);
+        assert_eq!(state.requests, vec!["remove me"]);
+    }
+}
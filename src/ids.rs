@@ -0,0 +1,293 @@
+/// Allocating `AgentId`s.
+///
+/// An `IdAllocator` lives in the context and issues the id of every new
+/// agent, so ids never collide, and is told when agents are removed, so
+/// ids that are kept around in messages or agent memory after their agent
+/// has gone can be recognised as stale (see `IdAllocator::status`).
+///
+/// * `IdAllocator::monotonic()` issues 0, 1, 2, ... and never reuses an
+///   id.
+/// * `IdAllocator::generational()` reuses the slots of removed agents,
+///   lowest first, but bumps a generation number kept in the high 32 bits
+///   of the id, so an id of a removed agent never matches its successor.
+///   A slot whose generation runs out is retired rather than wrapped
+///   around, so this holds however often a slot is reused.
+///
+/// Contexts that implement `IdContext` get `create_agent`, which allocates
+/// the id, builds the agent with it and returns the id, and
+/// `insert_agent`, which does the same for an agent that is already built.
+use std::collections::BTreeSet;
+
+use crate::map_context::MapContext;
+use crate::utils::{AgentId, SetIdOp};
+
+/// What an allocator knows about an id.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IdStatus {
+    /// The id belongs to an agent that hasn't been removed.
+    Live,
+    /// The id was issued, but its agent has been removed.
+    Stale,
+    /// The id was never issued by this allocator.
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+enum Scheme {
+    Monotonic {
+        next: u64,
+    },
+    Generational {
+        // One past `u32::MAX` marks a retired slot.
+        generations: Vec<u64>,
+        free: BTreeSet<u32>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct IdAllocator {
+    scheme: Scheme,
+    live: BTreeSet<AgentId>,
+}
+
+impl Default for IdAllocator {
+    fn default() -> Self {
+        Self::monotonic()
+    }
+}
+
+fn split(id: AgentId) -> (u32, u32) {
+    ((id.0 & 0xffff_ffff) as u32, (id.0 >> 32) as u32)
+}
+
+fn join(index: u32, generation: u32) -> AgentId {
+    AgentId((u64::from(generation) << 32) | u64::from(index))
+}
+
+impl IdAllocator {
+    pub fn monotonic() -> Self {
+        IdAllocator {
+            scheme: Scheme::Monotonic { next: 0 },
+            live: BTreeSet::new(),
+        }
+    }
+
+    pub fn generational() -> Self {
+        IdAllocator {
+            scheme: Scheme::Generational {
+                generations: vec![],
+                free: BTreeSet::new(),
+            },
+            live: BTreeSet::new(),
+        }
+    }
+
+    /// Issues a new id.
+    pub fn allocate(&mut self) -> AgentId {
+        let id = match &mut self.scheme {
+            Scheme::Monotonic { next } => {
+                let id = AgentId(*next);
+                *next += 1;
+                id
+            }
+            Scheme::Generational { generations, free } => {
+                let index = match free.iter().next().cloned() {
+                    Some(index) => {
+                        free.remove(&index);
+                        index
+                    }
+                    None => {
+                        generations.push(0);
+                        (generations.len() - 1) as u32
+                    }
+                };
+                join(index, generations[index as usize] as u32)
+            }
+        };
+        self.live.insert(id);
+        id
+    }
+
+    /// Marks the agent with `id` as removed. Returns false if the id
+    /// wasn't live.
+    pub fn release(&mut self, id: AgentId) -> bool {
+        if !self.live.remove(&id) {
+            return false;
+        }
+        if let Scheme::Generational { generations, free } = &mut self.scheme {
+            let (index, _) = split(id);
+            let generation = &mut generations[index as usize];
+            *generation += 1;
+            if *generation <= u64::from(u32::MAX) {
+                free.insert(index);
+            }
+        }
+        true
+    }
+
+    pub fn is_live(&self, id: AgentId) -> bool {
+        self.live.contains(&id)
+    }
+
+    pub fn status(&self, id: AgentId) -> IdStatus {
+        if self.is_live(id) {
+            return IdStatus::Live;
+        }
+        let issued = match &self.scheme {
+            Scheme::Monotonic { next } => id.0 < *next,
+            Scheme::Generational { generations, .. } => {
+                let (index, generation) = split(id);
+                generations
+                    .get(index as usize)
+                    .is_some_and(|current| u64::from(generation) < *current)
+            }
+        };
+        if issued {
+            IdStatus::Stale
+        } else {
+            IdStatus::Unknown
+        }
+    }
+}
+
+/// A context with an `IdAllocator`.
+pub trait IdContext {
+    fn ids(&self) -> &IdAllocator;
+    fn ids_mut(&mut self) -> &mut IdAllocator;
+
+    /// Allocates an id, inserts the agent `make` builds with it, and
+    /// returns the id.
+    fn create_agent<AGENT, F>(&mut self, make: F) -> AgentId
+    where
+        Self: MapContext<AGENT>,
+        F: FnOnce(AgentId) -> AGENT,
+    {
+        let id = self.ids_mut().allocate();
+        self.agents_mut().insert(id, make(id));
+        id
+    }
+
+    /// Allocates an id, sets it on `agent`, inserts it, and returns the
+    /// id.
+    fn insert_agent<AGENT>(&mut self, mut agent: AGENT) -> AgentId
+    where
+        Self: MapContext<AGENT>,
+        AGENT: SetIdOp,
+    {
+        self.create_agent(|id| {
+            agent.set_id(id);
+            agent
+        })
+    }
+
+    /// Removes an agent and releases its id. The id is left alone if
+    /// there was no agent with it.
+    fn destroy_agent<AGENT>(&mut self, id: AgentId) -> Option<AGENT>
+    where
+        Self: MapContext<AGENT>,
+    {
+        let agent = self.agents_mut().remove(&id);
+        if agent.is_some() {
+            self.ids_mut().release(id);
+        }
+        agent
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn test_monotonic() {
+        let mut ids = IdAllocator::monotonic();
+        let a = ids.allocate();
+        let b = ids.allocate();
+        assert_eq!((a, b), (AgentId(0), AgentId(1)));
+
+        assert!(ids.release(a));
+        assert!(!ids.release(a));
+        assert_eq!(ids.allocate(), AgentId(2));
+
+        assert_eq!(ids.status(a), IdStatus::Stale);
+        assert_eq!(ids.status(b), IdStatus::Live);
+        assert_eq!(ids.status(AgentId(3)), IdStatus::Unknown);
+    }
+
+    #[test]
+    fn test_generational() {
+        let mut ids = IdAllocator::generational();
+        let a = ids.allocate();
+        let b = ids.allocate();
+        ids.release(a);
+
+        // The slot is reused, but the old id is still told apart.
+        let c = ids.allocate();
+        assert_ne!(a, c);
+        assert_eq!(split(c), (0, 1));
+        assert_eq!(ids.status(a), IdStatus::Stale);
+        assert_eq!(ids.status(b), IdStatus::Live);
+        assert_eq!(ids.status(c), IdStatus::Live);
+        assert_eq!(ids.status(join(0, 2)), IdStatus::Unknown);
+        assert_eq!(ids.status(join(5, 0)), IdStatus::Unknown);
+    }
+
+    #[test]
+    fn test_exhausted_slot_is_retired() {
+        let last = join(0, u32::MAX);
+        let mut ids = IdAllocator {
+            scheme: Scheme::Generational {
+                generations: vec![u64::from(u32::MAX)],
+                free: BTreeSet::new(),
+            },
+            live: vec![last].into_iter().collect(),
+        };
+        assert!(ids.release(last));
+
+        assert_eq!(ids.allocate(), join(1, 0));
+        assert_eq!(ids.status(last), IdStatus::Stale);
+        assert_eq!(ids.status(join(0, 0)), IdStatus::Stale);
+    }
+
+    struct Context {
+        agents: BTreeMap<AgentId, u32>,
+        ids: IdAllocator,
+    }
+
+    impl MapContext<u32> for Context {
+        fn agents(&self) -> &BTreeMap<AgentId, u32> {
+            &self.agents
+        }
+        fn agents_mut(&mut self) -> &mut BTreeMap<AgentId, u32> {
+            &mut self.agents
+        }
+    }
+
+    impl IdContext for Context {
+        fn ids(&self) -> &IdAllocator {
+            &self.ids
+        }
+        fn ids_mut(&mut self) -> &mut IdAllocator {
+            &mut self.ids
+        }
+    }
+
+    #[test]
+    fn test_destroy_agent() {
+        let mut context = Context {
+            agents: BTreeMap::new(),
+            ids: IdAllocator::monotonic(),
+        };
+        let a = context.create_agent(|_| 1);
+        // Allocated, but its agent hasn't been inserted yet.
+        let b = context.ids_mut().allocate();
+
+        assert_eq!(context.destroy_agent(a), Some(1));
+        assert_eq!(context.destroy_agent::<u32>(b), None);
+        assert_eq!(context.ids().status(a), IdStatus::Stale);
+        assert_eq!(context.ids().status(b), IdStatus::Live);
+    }
+}
@@ -1,6 +1,26 @@
 pub mod act_map_if;
+pub mod act_map_mut;
+pub mod act_map_ref;
 pub mod behaviour;
+pub mod behaviour_ext;
+pub mod behaviour_mut;
+pub mod behaviour_tree;
 pub mod chain;
+pub mod conflicts;
+pub mod from_fn;
+pub mod fsm;
+pub mod ids;
 pub mod map_context;
+pub mod messaging;
+pub mod names;
+pub mod parallel;
+pub mod registry;
 pub mod remove_self;
+pub mod requests;
+pub mod sequence;
+pub mod simulation;
+pub mod try_behaviour;
+pub mod utility;
 pub mod utils;
+pub mod when;
+pub mod with_context;
@@ -2,9 +2,22 @@ use std::collections::BTreeMap;
 
 use crate::utils::AgentId;
 
+/// A context that keeps its agents in a map from their ids.
+///
+/// `agents_mut` is required, and `set_agents` is provided on top of it,
+/// rather than the other way round: `step_agents`, `IdContext` and the
+/// system request helpers update, insert and remove single agents in
+/// place, which `set_agents` could only do by rebuilding the whole map.
+/// This changed in 0.2: a context that used to implement `set_agents` only
+/// needs to return a mutable reference to the map it already holds, and
+/// anything else its `set_agents` did is no longer run by `step_agents`.
 pub trait MapContext<AGENT> {
-    fn set_agents(&mut self, agents: BTreeMap<AgentId, AGENT>);
+    /// Replaces all the agents.
+    fn set_agents(&mut self, agents: BTreeMap<AgentId, AGENT>) {
+        *self.agents_mut() = agents;
+    }
     fn agents(&self) -> &BTreeMap<AgentId, AGENT>;
+    fn agents_mut(&mut self) -> &mut BTreeMap<AgentId, AGENT>;
 }
 
 pub struct SimpleMapContext<AGENT> {
@@ -19,6 +32,12 @@ impl<AGENT> SimpleMapContext<AGENT> {
     }
 }
 
+impl<AGENT> Default for SimpleMapContext<AGENT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<AGENT> MapContext<AGENT> for SimpleMapContext<AGENT> {
     fn set_agents(&mut self, agents: BTreeMap<AgentId, AGENT>) {
         self.agents = agents;
@@ -27,4 +46,8 @@ impl<AGENT> MapContext<AGENT> for SimpleMapContext<AGENT> {
     fn agents(&self) -> &BTreeMap<AgentId, AGENT> {
         &self.agents
     }
+
+    fn agents_mut(&mut self) -> &mut BTreeMap<AgentId, AGENT> {
+        &mut self.agents
+    }
 }
@@ -0,0 +1,630 @@
+/// Messages between agents.
+///
+/// Agents put `Envelope`s in their outbox (see `OutboxOp`) and read the
+/// messages sent to them in the previous step from their inbox (see
+/// `InboxOp`). Between steps `gather_messages` empties the outboxes and
+/// `deliver_messages` routes the messages into the inboxes, or a
+/// `PostOffice` does, adding delays and a dead-letter queue.
+///
+/// `utils::MessageSystem` is implemented for `SimpleMapContext` on top of
+/// these. Contexts with names implement it by calling `gather_messages`
+/// and `PostOffice::deliver` with their own agents and resolver.
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::slice;
+
+use crate::map_context::SimpleMapContext;
+pub use crate::names::{NameResolver, NoNames};
+use crate::utils::{AgentId, BaseOp, MessageSystem};
+
+/// Why a message couldn't be delivered, because the agent or name it was
+/// sent to doesn't exist (any more).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryFailure {
+    /// There is no agent with this id.
+    NoSuchAgent(AgentId),
+    /// No agent has this name.
+    UnknownName(String),
+}
+
+/// A message that couldn't be delivered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadLetter<BODY> {
+    pub message: Envelope<BODY>,
+    pub reason: DeliveryFailure,
+}
+
+/// Who a message is for, as with HASH's `state.addMessage`: a single
+/// agent by id, every agent other than the sender, or an agent by name,
+/// resolved through the context's `NameResolver` (see `names`) when the
+/// message is delivered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recipient {
+    Agent(AgentId),
+    Broadcast,
+    Name(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope<BODY> {
+    pub to: Recipient,
+    pub from: AgentId,
+    pub body: BODY,
+    /// How many steps later than usual the message arrives.
+    pub delay: u64,
+}
+
+/// A message from an inbox, with its body viewed as a `T`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Received<T> {
+    pub from: AgentId,
+    pub body: T,
+}
+
+/// An iterator over the messages in an inbox that a projection accepts.
+pub struct Messages<'a, BODY, F> {
+    inbox: slice::Iter<'a, Envelope<BODY>>,
+    project: F,
+}
+
+impl<'a, BODY, T, F> Iterator for Messages<'a, BODY, F>
+where
+    F: FnMut(&'a BODY) -> Option<T>,
+{
+    type Item = Received<T>;
+
+    fn next(&mut self) -> Option<Received<T>> {
+        for message in &mut self.inbox {
+            if let Some(body) = (self.project)(&message.body) {
+                return Some(Received {
+                    from: message.from,
+                    body,
+                });
+            }
+        }
+        None
+    }
+}
+
+fn try_project<'a, BODY, T>(body: &'a BODY) -> Option<T>
+where
+    T: TryFrom<&'a BODY>,
+{
+    T::try_from(body).ok()
+}
+
+/// An agent state with an inbox of messages.
+///
+/// Behaviours usually only understand some of the message bodies an agent
+/// can receive, so `messages` and `messages_by` iterate over just the
+/// ones they accept. Both borrow from the inbox, so nothing is cloned or
+/// allocated.
+pub trait InboxOp<BODY> {
+    /// The messages delivered at the end of the previous step.
+    fn inbox(&self) -> &[Envelope<BODY>];
+    fn inbox_mut(&mut self) -> &mut Vec<Envelope<BODY>>;
+
+    /// The messages whose body converts to a `T`, usually a reference to
+    /// one variant of a `BODY` enum.
+    fn messages<'a, T>(&'a self) -> Messages<'a, BODY, fn(&'a BODY) -> Option<T>>
+    where
+        T: TryFrom<&'a BODY>,
+    {
+        self.messages_by(try_project::<BODY, T>)
+    }
+
+    /// The messages for which `project` returns a view of the body.
+    fn messages_by<'a, T, F>(&'a self, project: F) -> Messages<'a, BODY, F>
+    where
+        F: FnMut(&'a BODY) -> Option<T>,
+    {
+        Messages {
+            inbox: self.inbox().iter(),
+            project,
+        }
+    }
+}
+
+/// An agent state with an outbox of messages.
+pub trait OutboxOp<BODY> {
+    fn outbox_mut(&mut self) -> &mut Vec<Envelope<BODY>>;
+
+    fn send(&mut self, message: Envelope<BODY>) {
+        self.outbox_mut().push(message);
+    }
+
+    /// Sends `body` to the agent with id `to`.
+    fn send_to(&mut self, to: AgentId, body: BODY)
+    where
+        Self: BaseOp,
+    {
+        self.send_delayed(Recipient::Agent(to), body, 0);
+    }
+
+    /// Sends `body` to every other agent.
+    fn broadcast(&mut self, body: BODY)
+    where
+        Self: BaseOp,
+    {
+        self.send_delayed(Recipient::Broadcast, body, 0);
+    }
+
+    /// Sends `body` to the agent called `name`.
+    fn send_to_name(&mut self, name: &str, body: BODY)
+    where
+        Self: BaseOp,
+    {
+        self.send_delayed(Recipient::Name(name.to_string()), body, 0);
+    }
+
+    /// Sends `body` to `to`, arriving `delay` steps later than usual.
+    fn send_delayed(&mut self, to: Recipient, body: BODY, delay: u64)
+    where
+        Self: BaseOp,
+    {
+        let from = self.id();
+        self.send(Envelope {
+            to,
+            from,
+            body,
+            delay,
+        });
+    }
+
+    fn empty_outbox(&mut self) -> Vec<Envelope<BODY>> {
+        self.outbox_mut().drain(..).collect()
+    }
+}
+
+/// Empties the outbox of every agent, in agent id order.
+pub fn gather_messages<AGENT, BODY>(agents: &mut BTreeMap<AgentId, AGENT>) -> Vec<Envelope<BODY>>
+where
+    AGENT: OutboxOp<BODY>,
+{
+    let mut messages = vec![];
+    for agent in agents.values_mut() {
+        messages.append(agent.outbox_mut());
+    }
+    messages
+}
+
+/// Clears the inbox of every agent, then delivers `messages` in order,
+/// ignoring their delays.
+///
+/// Returns the messages that couldn't be delivered.
+pub fn deliver_messages<AGENT, BODY, R>(
+    agents: &mut BTreeMap<AgentId, AGENT>,
+    names: &R,
+    messages: Vec<Envelope<BODY>>,
+) -> Vec<DeadLetter<BODY>>
+where
+    AGENT: InboxOp<BODY>,
+    BODY: Clone,
+    R: NameResolver + ?Sized,
+{
+    for agent in agents.values_mut() {
+        agent.inbox_mut().clear();
+    }
+
+    let mut undelivered = vec![];
+    for message in messages {
+        let to = match &message.to {
+            Recipient::Agent(id) => Ok(*id),
+            Recipient::Name(name) => names
+                .resolve_id_from_name(name)
+                .ok_or_else(|| DeliveryFailure::UnknownName(name.clone())),
+            Recipient::Broadcast => {
+                for (id, agent) in agents.iter_mut() {
+                    if *id != message.from {
+                        agent.inbox_mut().push(message.clone());
+                    }
+                }
+                continue;
+            }
+        };
+        let agent = to.and_then(|id| agents.get_mut(&id).ok_or(DeliveryFailure::NoSuchAgent(id)));
+        match agent {
+            Ok(agent) => agent.inbox_mut().push(message),
+            Err(reason) => undelivered.push(DeadLetter { message, reason }),
+        }
+    }
+    undelivered
+}
+
+/// Where a message is in the delivery queue: the step it is due, then
+/// its sender, then the order it was sent in.
+type QueueKey = (u64, AgentId, u64);
+
+/// Delivers messages, holding delayed ones until they are due, and keeps
+/// the ones that couldn't be delivered.
+///
+/// A context keeps one of these next to its agents and calls `deliver`
+/// from its `MessageSystem::deliver_messages`, once per step.
+///
+/// Messages normally arrive in the step after they are sent, and a
+/// message with a `delay` of `n` arrives `n` steps later than that.
+/// Delayed messages wait in a queue ordered by the step they are due, then
+/// sender and then the order they were sent in, so delivery is
+/// deterministic. The queue is part of the context that owns the
+/// `PostOffice`, so messages still in flight are kept in any snapshot of
+/// it.
+#[derive(Debug, Clone)]
+pub struct PostOffice<BODY> {
+    step: u64,
+    sent: u64,
+    queue: BTreeMap<QueueKey, Envelope<BODY>>,
+    dead_letters: Vec<DeadLetter<BODY>>,
+    bounce: Option<fn(&DeadLetter<BODY>) -> BODY>,
+}
+
+impl<BODY> Default for PostOffice<BODY> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<BODY> PostOffice<BODY> {
+    pub fn new() -> Self {
+        PostOffice {
+            step: 0,
+            sent: 0,
+            queue: BTreeMap::new(),
+            dead_letters: vec![],
+            bounce: None,
+        }
+    }
+
+    /// Bounces undeliverable messages back to their sender, with the body
+    /// made by `bounce`. The bounce is delivered along with the other
+    /// messages, so the sender sees it in the next step. It comes "from"
+    /// the sender, and if the sender has gone too it is dropped.
+    pub fn with_bounce(mut self, bounce: fn(&DeadLetter<BODY>) -> BODY) -> Self {
+        self.bounce = Some(bounce);
+        self
+    }
+
+    /// Queues `messages`, then delivers the messages that are due as
+    /// `deliver_messages` does, adding the ones that couldn't be delivered
+    /// to the dead-letter queue (see `dead_letters`), and bouncing them if
+    /// set up `with_bounce`.
+    pub fn deliver<AGENT, R>(
+        &mut self,
+        agents: &mut BTreeMap<AgentId, AGENT>,
+        names: &R,
+        messages: Vec<Envelope<BODY>>,
+    ) where
+        AGENT: InboxOp<BODY>,
+        BODY: Clone,
+        R: NameResolver + ?Sized,
+    {
+        for message in messages {
+            let key = (self.step + message.delay, message.from, self.sent);
+            self.sent += 1;
+            self.queue.insert(key, message);
+        }
+        let later = self.queue.split_off(&(self.step + 1, AgentId(0), 0));
+        let due = std::mem::replace(&mut self.queue, later);
+        self.step += 1;
+
+        let undelivered = deliver_messages(agents, names, due.into_values().collect());
+        if let Some(bounce) = self.bounce {
+            for dead_letter in &undelivered {
+                let sender = dead_letter.message.from;
+                if let Some(agent) = agents.get_mut(&sender) {
+                    agent.inbox_mut().push(Envelope {
+                        to: Recipient::Agent(sender),
+                        from: sender,
+                        body: bounce(dead_letter),
+                        delay: 0,
+                    });
+                }
+            }
+        }
+        self.dead_letters.extend(undelivered);
+    }
+
+    /// The messages waiting to be delivered in a later step, in the order
+    /// they will be delivered.
+    pub fn pending(&self) -> impl Iterator<Item = &Envelope<BODY>> {
+        self.queue.values()
+    }
+
+    /// Every message that couldn't be delivered, oldest first.
+    ///
+    /// The `PostOffice` never drops these itself, so the queue grows for
+    /// as long as messages go astray. Empty it with `take_dead_letters`,
+    /// e.g. once a step.
+    pub fn dead_letters(&self) -> &[DeadLetter<BODY>] {
+        &self.dead_letters
+    }
+
+    /// Empties the dead-letter queue.
+    pub fn take_dead_letters(&mut self) -> Vec<DeadLetter<BODY>> {
+        std::mem::take(&mut self.dead_letters)
+    }
+}
+
+/// Agents in a `SimpleMapContext` have no names, so messages addressed by
+/// name are dropped, as are messages to agents that don't exist.
+///
+/// It has no `PostOffice` to hold delayed messages in, so messages with a
+/// non-zero `delay` are dead letters too, and dropped. Use a context with
+/// a `PostOffice` for delays.
+impl<AGENT, BODY> MessageSystem<Envelope<BODY>> for SimpleMapContext<AGENT>
+where
+    AGENT: InboxOp<BODY> + OutboxOp<BODY>,
+    BODY: Clone,
+{
+    fn gather_messages(&mut self) -> Vec<Envelope<BODY>> {
+        gather_messages(&mut self.agents)
+    }
+
+    fn deliver_messages(&mut self, messages: Vec<Envelope<BODY>>) {
+        let (now, _delayed): (Vec<_>, Vec<_>) =
+            messages.into_iter().partition(|message| message.delay == 0);
+        deliver_messages(&mut self.agents, &NoNames, now);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Mailbox {
+        id: AgentId,
+        inbox: Vec<Envelope<&'static str>>,
+        outbox: Vec<Envelope<&'static str>>,
+    }
+
+    impl BaseOp for Mailbox {
+        fn id(&self) -> AgentId {
+            self.id
+        }
+    }
+
+    impl InboxOp<&'static str> for Mailbox {
+        fn inbox(&self) -> &[Envelope<&'static str>] {
+            &self.inbox
+        }
+        fn inbox_mut(&mut self) -> &mut Vec<Envelope<&'static str>> {
+            &mut self.inbox
+        }
+    }
+
+    impl OutboxOp<&'static str> for Mailbox {
+        fn outbox_mut(&mut self) -> &mut Vec<Envelope<&'static str>> {
+            &mut self.outbox
+        }
+    }
+
+    fn agents() -> BTreeMap<AgentId, Mailbox> {
+        (1..=3)
+            .map(|i| {
+                let mailbox = Mailbox {
+                    id: AgentId(i),
+                    inbox: vec![],
+                    outbox: vec![],
+                };
+                (AgentId(i), mailbox)
+            })
+            .collect()
+    }
+
+    fn bodies(agent: &Mailbox) -> Vec<&'static str> {
+        agent.inbox().iter().map(|m| m.body).collect()
+    }
+
+    #[test]
+    fn test_addressing() {
+        let mut agents = agents();
+        let mut names = BTreeMap::new();
+        names.insert("carol".to_string(), AgentId(3));
+
+        let first = agents.get_mut(&AgentId(1)).unwrap();
+        first.send_to(AgentId(2), "direct");
+        first.broadcast("everyone");
+        first.send_to_name("carol", "named");
+        first.send_to_name("dave", "lost");
+        first.send_to(AgentId(9), "gone");
+
+        let messages = gather_messages(&mut agents);
+        assert_eq!(messages.len(), 5);
+        assert!(agents[&AgentId(1)].outbox.is_empty());
+
+        let undelivered = deliver_messages(&mut agents, &names, messages);
+        let undelivered: Vec<_> = undelivered
+            .iter()
+            .map(|d| (d.message.body, d.reason.clone()))
+            .collect();
+        assert_eq!(
+            undelivered,
+            vec![
+                ("lost", DeliveryFailure::UnknownName("dave".to_string())),
+                ("gone", DeliveryFailure::NoSuchAgent(AgentId(9))),
+            ]
+        );
+
+        assert!(bodies(&agents[&AgentId(1)]).is_empty());
+        assert_eq!(bodies(&agents[&AgentId(2)]), vec!["direct", "everyone"]);
+        assert_eq!(bodies(&agents[&AgentId(3)]), vec!["everyone", "named"]);
+
+        // The next delivery replaces the inboxes.
+        deliver_messages(&mut agents, &names, vec![]);
+        assert!(agents.values().all(|a| a.inbox.is_empty()));
+    }
+
+    #[test]
+    fn test_post_office_bounces() {
+        let mut agents = agents();
+        let names = NoNames;
+        let mut post_office = PostOffice::new().with_bounce(|_| "bounced");
+
+        agents.remove(&AgentId(3));
+        let first = agents.get_mut(&AgentId(1)).unwrap();
+        first.send_to(AgentId(3), "to the removed agent");
+        first.send_to(AgentId(2), "hello");
+        // The bounce for this one has nowhere to go.
+        let unknown = Envelope {
+            to: Recipient::Agent(AgentId(8)),
+            from: AgentId(7),
+            body: "from nobody",
+            delay: 0,
+        };
+
+        let mut messages = gather_messages(&mut agents);
+        messages.push(unknown);
+        post_office.deliver(&mut agents, &names, messages);
+
+        assert_eq!(bodies(&agents[&AgentId(1)]), vec!["bounced"]);
+        assert_eq!(bodies(&agents[&AgentId(2)]), vec!["hello"]);
+        let dead: Vec<_> = post_office
+            .dead_letters()
+            .iter()
+            .map(|d| d.message.body)
+            .collect();
+        assert_eq!(dead, vec!["to the removed agent", "from nobody"]);
+
+        // The queue keeps building up until it is emptied.
+        post_office.deliver(&mut agents, &names, vec![]);
+        assert_eq!(post_office.take_dead_letters().len(), 2);
+        assert!(post_office.dead_letters().is_empty());
+    }
+
+    #[test]
+    fn test_delayed_delivery() {
+        let mut agents = agents();
+        let names = NoNames;
+        let mut post_office = PostOffice::new();
+
+        let third = agents.get_mut(&AgentId(3)).unwrap();
+        third.send_delayed(Recipient::Agent(AgentId(1)), "from 3, delay 1", 1);
+        third.send_to(AgentId(1), "from 3");
+        let second = agents.get_mut(&AgentId(2)).unwrap();
+        second.send_delayed(Recipient::Agent(AgentId(1)), "from 2, delay 2", 2);
+        second.send_delayed(Recipient::Agent(AgentId(1)), "from 2, delay 1", 1);
+
+        let messages = gather_messages(&mut agents);
+        post_office.deliver(&mut agents, &names, messages);
+        assert_eq!(bodies(&agents[&AgentId(1)]), vec!["from 3"]);
+        assert_eq!(post_office.pending().count(), 3);
+
+        // A snapshot keeps the messages in flight.
+        let snapshot = (agents.clone(), post_office.clone());
+
+        // Ties are broken by sender, then the order they were sent in.
+        agents
+            .get_mut(&AgentId(2))
+            .unwrap()
+            .send_to(AgentId(1), "from 2, sent later");
+        let messages = gather_messages(&mut agents);
+        post_office.deliver(&mut agents, &names, messages);
+        assert_eq!(
+            bodies(&agents[&AgentId(1)]),
+            vec!["from 2, delay 1", "from 2, sent later", "from 3, delay 1"]
+        );
+
+        post_office.deliver(&mut agents, &names, vec![]);
+        assert_eq!(bodies(&agents[&AgentId(1)]), vec!["from 2, delay 2"]);
+        assert_eq!(post_office.pending().count(), 0);
+
+        let (mut agents, mut post_office) = snapshot;
+        post_office.deliver(&mut agents, &names, vec![]);
+        assert_eq!(
+            bodies(&agents[&AgentId(1)]),
+            vec!["from 2, delay 1", "from 3, delay 1"]
+        );
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Body {
+        Ping(u32),
+        Text(String),
+    }
+
+    struct Ping(u32);
+
+    impl<'a> TryFrom<&'a Body> for Ping {
+        type Error = ();
+        fn try_from(body: &'a Body) -> Result<Ping, ()> {
+            match body {
+                Body::Ping(n) => Ok(Ping(*n)),
+                _ => Err(()),
+            }
+        }
+    }
+
+    struct Typed {
+        inbox: Vec<Envelope<Body>>,
+    }
+
+    impl InboxOp<Body> for Typed {
+        fn inbox(&self) -> &[Envelope<Body>] {
+            &self.inbox
+        }
+        fn inbox_mut(&mut self) -> &mut Vec<Envelope<Body>> {
+            &mut self.inbox
+        }
+    }
+
+    #[test]
+    fn test_typed_inbox() {
+        let message = |from, body| Envelope {
+            to: Recipient::Agent(AgentId(0)),
+            from: AgentId(from),
+            body,
+            delay: 0,
+        };
+        let agent = Typed {
+            inbox: vec![
+                message(1, Body::Ping(3)),
+                message(2, Body::Text("hello".to_string())),
+                message(3, Body::Ping(4)),
+            ],
+        };
+
+        let pings: Vec<(AgentId, u32)> = agent
+            .messages::<Ping>()
+            .map(|m| (m.from, m.body.0))
+            .collect();
+        assert_eq!(pings, vec![(AgentId(1), 3), (AgentId(3), 4)]);
+
+        // Projections can borrow from the inbox.
+        let texts: Vec<Received<&str>> = agent
+            .messages_by(|body| match body {
+                Body::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            texts,
+            vec![Received {
+                from: AgentId(2),
+                body: "hello"
+            }]
+        );
+    }
+
+    #[test]
+    fn test_simple_map_context() {
+        let mut context = SimpleMapContext::new();
+        context.agents = agents();
+
+        context
+            .agents
+            .get_mut(&AgentId(3))
+            .unwrap()
+            .send_to(AgentId(1), "hi");
+        context.agents.get_mut(&AgentId(3)).unwrap().send_delayed(
+            Recipient::Agent(AgentId(1)),
+            "later",
+            1,
+        );
+        let messages = MessageSystem::<Envelope<&str>>::gather_messages(&mut context);
+        context.deliver_messages(messages);
+
+        // The delayed message has nowhere to wait, so it is dropped.
+        assert_eq!(bodies(&context.agents[&AgentId(1)]), vec!["hi"]);
+    }
+}
@@ -0,0 +1,243 @@
+/// Names and tags for agents.
+///
+/// A `NameRegistry` lives in the context. Agents are registered into it
+/// when they are created: each name belongs to a single agent, while a tag
+/// can be shared by any number of agents. Behaviours look names up through
+/// the `NameResolver` trait.
+///
+/// Contexts that implement `NamedContext` get `NameResolver` for free. Their
+/// names and tags must go when their agent does. The standard
+/// `requests::Request`s register and unregister them along with the agent,
+/// and other contexts remove agents with `NamedContext::remove_agent`.
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::map_context::MapContext;
+use crate::utils::AgentId;
+
+/// A context that can find agents by name.
+pub trait NameResolver {
+    fn resolve_id_from_name(&self, name: &str) -> Option<AgentId>;
+}
+
+impl NameResolver for BTreeMap<String, AgentId> {
+    fn resolve_id_from_name(&self, name: &str) -> Option<AgentId> {
+        self.get(name).cloned()
+    }
+}
+
+/// A resolver for contexts whose agents have no names.
+pub struct NoNames;
+
+impl NameResolver for NoNames {
+    fn resolve_id_from_name(&self, _name: &str) -> Option<AgentId> {
+        None
+    }
+}
+
+/// A name that already belongs to another agent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTaken {
+    pub name: String,
+    pub owner: AgentId,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Entries {
+    names: Vec<String>,
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NameRegistry {
+    names: BTreeMap<String, AgentId>,
+    tags: BTreeMap<String, BTreeSet<AgentId>>,
+    agents: BTreeMap<AgentId, Entries>,
+}
+
+impl NameRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gives agent `id` the unique name `name`. Registering a name an
+    /// agent already has does nothing.
+    pub fn register(&mut self, id: AgentId, name: &str) -> Result<(), NameTaken> {
+        match self.names.get(name) {
+            Some(owner) if *owner == id => Ok(()),
+            Some(owner) => Err(NameTaken {
+                name: name.to_string(),
+                owner: *owner,
+            }),
+            None => {
+                self.names.insert(name.to_string(), id);
+                self.agents
+                    .entry(id)
+                    .or_default()
+                    .names
+                    .push(name.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    /// Tags agent `id` with `tag`, which other agents may share.
+    pub fn tag(&mut self, id: AgentId, tag: &str) {
+        if self.tags.entry(tag.to_string()).or_default().insert(id) {
+            self.agents
+                .entry(id)
+                .or_default()
+                .tags
+                .push(tag.to_string());
+        }
+    }
+
+    /// Removes every name and tag of agent `id`.
+    pub fn unregister(&mut self, id: AgentId) {
+        let entries = match self.agents.remove(&id) {
+            Some(entries) => entries,
+            None => return,
+        };
+        for name in entries.names {
+            self.names.remove(&name);
+        }
+        for tag in entries.tags {
+            if let Some(ids) = self.tags.get_mut(&tag) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.tags.remove(&tag);
+                }
+            }
+        }
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<AgentId> {
+        self.names.get(name).cloned()
+    }
+
+    /// The agents tagged with `tag`, in id order.
+    pub fn tagged<'a>(&'a self, tag: &str) -> impl Iterator<Item = AgentId> + 'a {
+        self.tags.get(tag).into_iter().flatten().cloned()
+    }
+
+    pub fn names_of(&self, id: AgentId) -> &[String] {
+        self.agents.get(&id).map_or(&[], |e| &e.names)
+    }
+
+    pub fn tags_of(&self, id: AgentId) -> &[String] {
+        self.agents.get(&id).map_or(&[], |e| &e.tags)
+    }
+}
+
+impl NameResolver for NameRegistry {
+    fn resolve_id_from_name(&self, name: &str) -> Option<AgentId> {
+        self.resolve(name)
+    }
+}
+
+/// A context with a `NameRegistry`.
+pub trait NamedContext {
+    fn names(&self) -> &NameRegistry;
+    fn names_mut(&mut self) -> &mut NameRegistry;
+
+    /// Unregisters the names and tags of an agent that has been removed.
+    fn forget_agent(&mut self, id: AgentId) {
+        self.names_mut().unregister(id);
+    }
+
+    /// Removes an agent along with its names and tags.
+    fn remove_agent<AGENT>(&mut self, id: AgentId) -> Option<AGENT>
+    where
+        Self: MapContext<AGENT>,
+    {
+        self.forget_agent(id);
+        self.agents_mut().remove(&id)
+    }
+}
+
+impl<T> NameResolver for T
+where
+    T: NamedContext,
+{
+    fn resolve_id_from_name(&self, name: &str) -> Option<AgentId> {
+        self.names().resolve(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::map_context::SimpleMapContext;
+
+    struct Context {
+        agents: SimpleMapContext<&'static str>,
+        names: NameRegistry,
+    }
+
+    impl MapContext<&'static str> for Context {
+        fn agents(&self) -> &BTreeMap<AgentId, &'static str> {
+            &self.agents.agents
+        }
+        fn agents_mut(&mut self) -> &mut BTreeMap<AgentId, &'static str> {
+            &mut self.agents.agents
+        }
+    }
+
+    impl NamedContext for Context {
+        fn names(&self) -> &NameRegistry {
+            &self.names
+        }
+        fn names_mut(&mut self) -> &mut NameRegistry {
+            &mut self.names
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut names = NameRegistry::new();
+        names.register(AgentId(1), "alice").unwrap();
+        names.register(AgentId(1), "alice").unwrap();
+        assert_eq!(
+            names.register(AgentId(2), "alice"),
+            Err(NameTaken {
+                name: "alice".to_string(),
+                owner: AgentId(1)
+            })
+        );
+        names.register(AgentId(2), "bob").unwrap();
+        names.tag(AgentId(2), "boid");
+        names.tag(AgentId(1), "boid");
+        names.tag(AgentId(1), "boid");
+
+        assert_eq!(names.resolve("bob"), Some(AgentId(2)));
+        let boids: Vec<AgentId> = names.tagged("boid").collect();
+        assert_eq!(boids, vec![AgentId(1), AgentId(2)]);
+        assert_eq!(names.tags_of(AgentId(1)), ["boid".to_string()]);
+
+        names.unregister(AgentId(1));
+        assert_eq!(names.resolve("alice"), None);
+        assert!(names.names_of(AgentId(1)).is_empty());
+        let boids: Vec<AgentId> = names.tagged("boid").collect();
+        assert_eq!(boids, vec![AgentId(2)]);
+
+        // The name is free again.
+        names.register(AgentId(3), "alice").unwrap();
+    }
+
+    #[test]
+    fn test_named_context_cleans_up() {
+        let mut context = Context {
+            agents: SimpleMapContext::new(),
+            names: NameRegistry::new(),
+        };
+        context.agents.agents.insert(AgentId(1), "Alice's state");
+        context.names.register(AgentId(1), "alice").unwrap();
+
+        let resolver: &dyn NameResolver = &context;
+        assert_eq!(resolver.resolve_id_from_name("alice"), Some(AgentId(1)));
+
+        assert_eq!(context.remove_agent(AgentId(1)), Some("Alice's state"));
+        assert_eq!(context.resolve_id_from_name("alice"), None);
+        assert!(context.agents.agents.is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+/// Creates a composite behaviour that applies several behaviours to the
+/// same input state and then merges their results.
+///
+/// Unlike `Chain` and `Sequence`, where each stage sees the edits of the
+/// previous one, every branch of a `Parallel` acts on the original state.
+/// The branch results are handed to the merge function as an array, in the
+/// same order as the branches, together with the original state.
+///
+/// This is usually built with `parallel`. As with `Sequence` the branches
+/// are given as a tuple (of up to 12 behaviours) and statically dispatched.
+use std::ops::{Add, Mul};
+
+use crate::behaviour::Behaviour;
+use crate::behaviour_ext::BehaviourExt;
+
+pub struct Parallel<T, M> {
+    branches: T,
+    merge: M,
+}
+
+impl<T, M> BehaviourExt for Parallel<T, M> {}
+
+pub fn parallel<T, M>(branches: T, merge: M) -> Parallel<T, M> {
+    Parallel { branches, merge }
+}
+
+macro_rules! impl_parallel {
+    ($n:literal; $($name:ident $idx:tt),+) => {
+        impl<STATE, CONTEXT, M, $($name),+> Behaviour<STATE, CONTEXT>
+            for Parallel<($($name,)+), M>
+        where
+            $($name: Behaviour<STATE, CONTEXT>,)+
+            M: Fn(&STATE, [STATE; $n]) -> STATE,
+        {
+            fn act(&self, state: &STATE, context: &CONTEXT) -> STATE {
+                let results = [$(self.branches.$idx.act(state, context),)+];
+                (self.merge)(state, results)
+            }
+        }
+    };
+}
+
+impl_parallel!(1; A 0);
+impl_parallel!(2; A 0, B 1);
+impl_parallel!(3; A 0, B 1, C 2);
+impl_parallel!(4; A 0, B 1, C 2, D 3);
+impl_parallel!(5; A 0, B 1, C 2, D 3, E 4);
+impl_parallel!(6; A 0, B 1, C 2, D 3, E 4, F 5);
+impl_parallel!(7; A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_parallel!(8; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+impl_parallel!(9; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8);
+impl_parallel!(10; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9);
+impl_parallel!(11; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10);
+impl_parallel!(12; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11);
+
+/// A merge function for `Parallel` that takes a weighted sum of a vector
+/// quantity from each branch result.
+///
+/// `get` reads the vector (e.g. a steering direction) from each branch
+/// result, these are scaled by the matching weight and summed, and `set`
+/// writes the sum into a copy of the original state. Any other changes the
+/// branches made are discarded.
+pub fn weighted_merge<STATE, V, G, S, const N: usize>(
+    weights: [f32; N],
+    get: G,
+    set: S,
+) -> impl Fn(&STATE, [STATE; N]) -> STATE
+where
+    STATE: Clone,
+    V: Add<Output = V> + Mul<f32, Output = V>,
+    G: Fn(&STATE) -> V,
+    S: Fn(&mut STATE, V),
+{
+    move |state: &STATE, results: [STATE; N]| {
+        let total = weights
+            .iter()
+            .zip(results.iter())
+            .map(|(w, result)| get(result) * *w)
+            .reduce(|a, b| a + b);
+        let mut state = state.clone();
+        if let Some(total) = total {
+            set(&mut state, total);
+        }
+        state
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    struct Add(u32);
+
+    impl Behaviour<u32, ()> for Add {
+        fn act(&self, state: &u32, _context: &()) -> u32 {
+            state + self.0
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Steer {
+        direction: f32,
+    }
+
+    struct SteerTo(f32);
+
+    impl Behaviour<Steer, ()> for SteerTo {
+        fn act(&self, _state: &Steer, _context: &()) -> Steer {
+            Steer { direction: self.0 }
+        }
+    }
+
+    #[test]
+    fn test_parallel_sees_original_state() {
+        let b = parallel((Add(1), Add(10)), |_state: &u32, [a, b]: [u32; 2]| a * b);
+
+        let _m: &dyn Behaviour<u32, ()> = &b;
+
+        assert_eq!(b.act(&1, &()), 22);
+    }
+
+    #[test]
+    fn test_weighted_merge() {
+        let b = parallel(
+            (SteerTo(1.0), SteerTo(2.0), SteerTo(4.0)),
+            weighted_merge(
+                [1.0, 0.5, 0.25],
+                |s: &Steer| s.direction,
+                |s: &mut Steer, d| s.direction = d,
+            ),
+        );
+
+        assert_eq!(
+            b.act(&Steer { direction: 0.0 }, &()),
+            Steer { direction: 3.0 }
+        );
+    }
+}
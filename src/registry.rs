@@ -0,0 +1,231 @@
+/// Behaviours looked up by name at runtime, as in HASH where each agent
+/// carries a `behaviors: ["flock.js", ...]` list.
+///
+/// A `BehaviourRegistry` maps names to boxed behaviours. Agents keep their
+/// own list of behaviour names (see `BehaviourListOp`), and the registry,
+/// used as a `TryBehaviour`, applies the named behaviours in list order.
+/// A name that isn't registered fails the agent with `UnknownBehaviour`,
+/// which `utils::try_step_agents` handles according to its `ErrorPolicy`.
+///
+/// Since the list is part of the agent state, agents can add or remove
+/// behaviours from themselves, or set up the list of the children they
+/// create. The registry reads the list from the state the step started
+/// with, so changes take effect from the next step.
+///
+/// This is opt-in: it costs a virtual call per behaviour and a lookup per
+/// name, so the statically dispatched combinators remain the default.
+use std::collections::BTreeMap;
+
+use crate::behaviour::Behaviour;
+use crate::try_behaviour::TryBehaviour;
+
+/// An agent state that carries a list of behaviour names.
+pub trait BehaviourListOp {
+    fn behaviours(&self) -> &[String];
+    fn behaviours_mut(&mut self) -> &mut Vec<String>;
+
+    /// Appends `name` to the list, unless it is already there.
+    fn add_behaviour(&mut self, name: &str) {
+        if !self.has_behaviour(name) {
+            self.behaviours_mut().push(name.to_string());
+        }
+    }
+
+    /// Removes every occurrence of `name` from the list.
+    fn remove_behaviour(&mut self, name: &str) {
+        self.behaviours_mut().retain(|b| b != name);
+    }
+
+    fn has_behaviour(&self, name: &str) -> bool {
+        self.behaviours().iter().any(|b| b == name)
+    }
+}
+
+/// An agent's list named a behaviour that isn't registered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownBehaviour(pub String);
+
+pub struct BehaviourRegistry<STATE, CONTEXT> {
+    behaviours: BTreeMap<String, Box<dyn Behaviour<STATE, CONTEXT>>>,
+}
+
+impl<STATE, CONTEXT> Default for BehaviourRegistry<STATE, CONTEXT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<STATE, CONTEXT> BehaviourRegistry<STATE, CONTEXT> {
+    pub fn new() -> Self {
+        BehaviourRegistry {
+            behaviours: BTreeMap::new(),
+        }
+    }
+
+    /// Registers `behaviour` under `name`, replacing any behaviour
+    /// already registered under it.
+    pub fn register<B>(mut self, name: &str, behaviour: B) -> Self
+    where
+        B: Behaviour<STATE, CONTEXT> + 'static,
+    {
+        self.behaviours
+            .insert(name.to_string(), Box::new(behaviour));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Behaviour<STATE, CONTEXT>> {
+        self.behaviours.get(name).map(|b| b.as_ref())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.behaviours.contains_key(name)
+    }
+
+    /// Applies the behaviours named in the agent's list in order, or
+    /// returns the first name that isn't registered. Returns `Ok(None)`
+    /// if no behaviour changed the state.
+    pub fn try_act_changed(
+        &self,
+        state: &STATE,
+        context: &CONTEXT,
+    ) -> Result<Option<STATE>, UnknownBehaviour>
+    where
+        STATE: BehaviourListOp,
+    {
+        let mut current: Option<STATE> = None;
+        for name in state.behaviours() {
+            let behaviour = self
+                .get(name)
+                .ok_or_else(|| UnknownBehaviour(name.clone()))?;
+            let input = current.as_ref().unwrap_or(state);
+            if let Some(next) = behaviour.act_changed(input, context) {
+                current = Some(next);
+            }
+        }
+        Ok(current)
+    }
+}
+
+impl<STATE, CONTEXT> TryBehaviour<STATE, CONTEXT> for BehaviourRegistry<STATE, CONTEXT>
+where
+    STATE: BehaviourListOp + Clone,
+{
+    type Error = UnknownBehaviour;
+    fn try_act(&self, state: &STATE, context: &CONTEXT) -> Result<STATE, UnknownBehaviour> {
+        Ok(self
+            .try_act_changed(state, context)?
+            .unwrap_or_else(|| state.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::from_fn::from_fn;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Counter {
+        value: u32,
+        behaviours: Vec<String>,
+    }
+
+    impl BehaviourListOp for Counter {
+        fn behaviours(&self) -> &[String] {
+            &self.behaviours
+        }
+        fn behaviours_mut(&mut self) -> &mut Vec<String> {
+            &mut self.behaviours
+        }
+    }
+
+    struct Increment {}
+
+    impl Behaviour<Counter, ()> for Increment {
+        fn act(&self, state: &Counter, _context: &()) -> Counter {
+            let mut state = state.clone();
+            state.value += 1;
+            state
+        }
+    }
+
+    fn registry() -> BehaviourRegistry<Counter, ()> {
+        BehaviourRegistry::new()
+            .register("increment", Increment {})
+            .register(
+                "double",
+                from_fn(|s: &Counter, _: &()| Counter {
+                    value: s.value * 2,
+                    ..s.clone()
+                }),
+            )
+            // Stops incrementing once the value gets large.
+            .register(
+                "limit",
+                from_fn(|s: &Counter, _: &()| {
+                    let mut s = s.clone();
+                    if s.value >= 10 {
+                        s.remove_behaviour("increment");
+                    }
+                    s
+                }),
+            )
+    }
+
+    fn counter(value: u32, behaviours: &[&str]) -> Counter {
+        Counter {
+            value,
+            behaviours: behaviours.iter().map(|b| b.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_registry_applies_list_in_order() {
+        let registry = registry();
+        let _m: &dyn TryBehaviour<Counter, (), Error = UnknownBehaviour> = &registry;
+
+        let act = |state: Counter| registry.try_act(&state, &()).unwrap();
+        assert_eq!(act(counter(1, &["increment", "double"])).value, 4);
+        assert_eq!(act(counter(1, &["double", "increment"])).value, 3);
+        assert_eq!(registry.try_act_changed(&counter(1, &[]), &()), Ok(None));
+    }
+
+    #[test]
+    fn test_agents_change_their_own_list() {
+        let registry = registry();
+
+        let mut state = counter(8, &["increment", "limit"]);
+        state = registry.try_act(&state, &()).unwrap();
+        assert_eq!(state.value, 9);
+        assert!(state.has_behaviour("increment"));
+
+        state = registry.try_act(&state, &()).unwrap();
+        assert_eq!(state.value, 10);
+        assert!(!state.has_behaviour("increment"));
+
+        state = registry.try_act(&state, &()).unwrap();
+        assert_eq!(state.value, 10);
+
+        state.add_behaviour("double");
+        state.add_behaviour("double");
+        assert_eq!(state.behaviours, vec!["limit", "double"]);
+        assert_eq!(registry.try_act(&state, &()).unwrap().value, 20);
+    }
+
+    #[test]
+    fn test_unknown_behaviour() {
+        let registry = registry();
+
+        assert_eq!(
+            registry.try_act(&counter(1, &["increment", "flock.js"]), &()),
+            Err(UnknownBehaviour("flock.js".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_boxed_behaviours() {
+        let boxed: Box<dyn Behaviour<Counter, ()>> = Box::new(Increment {});
+        let chained = crate::chain::Chain::chain(boxed, Box::new(Increment {}));
+        assert_eq!(chained.act(&counter(1, &[]), &()).value, 3);
+    }
+}
@@ -1,7 +1,8 @@
 use crate::behaviour::Behaviour;
+use crate::behaviour_ext::BehaviourExt;
 use crate::utils::{AgentId, BaseOp, SystemOp};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct RemoveAgent(pub AgentId);
 
 pub struct RemoveSelfBehaviour {}
@@ -17,3 +18,5 @@ where
         state
     }
 }
+
+impl BehaviourExt for RemoveSelfBehaviour {}
@@ -0,0 +1,411 @@
+/// The system requests most simulations need.
+///
+/// `Request<AGENT, CUSTOM, MODIFY>` creates, removes or modifies agents, or
+/// carries a request of the simulation's own type `CUSTOM`. Any context
+/// that implements `MapContext`, `IdContext`, `NamedContext` and
+/// `RequestHandler` gets a `System<Request<AGENT, CUSTOM, MODIFY>>`
+/// implementation that creates and removes agents itself, registering and
+/// unregistering their names and tags along with them, and passes
+/// modifications and custom requests to the `RequestHandler`. A context
+/// that needs to handle a step's requests as a batch overrides
+/// `RequestHandler::apply_requests`.
+///
+/// Requests are plain data, so they can be compared, logged and kept in
+/// snapshots. Simulations without custom requests or modifications use the
+/// default `Infallible` for them, and handle them with `match request {}`.
+///
+/// `Request::phase` is a `RequestOrder` that applies all removals, then
+/// all modifications, then all creations, and then the custom requests.
+/// `Request::phase_and_target` also sorts the removals and modifications
+/// by the agent they target.
+use std::convert::Infallible;
+
+use crate::ids::IdContext;
+use crate::map_context::MapContext;
+use crate::names::{NameTaken, NamedContext};
+use crate::utils::{AgentId, SetIdOp, System};
+
+pub use crate::remove_self::RemoveAgent;
+
+/// Inserts the agent, with its id set to a newly allocated one, and
+/// registers its names and tags under that id.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CreateAgent<AGENT> {
+    pub agent: AGENT,
+    pub names: Vec<String>,
+    pub tags: Vec<String>,
+}
+
+impl<AGENT> CreateAgent<AGENT> {
+    pub fn new(agent: AGENT) -> Self {
+        CreateAgent {
+            agent,
+            names: vec![],
+            tags: vec![],
+        }
+    }
+
+    pub fn named(mut self, name: &str) -> Self {
+        self.names.push(name.to_string());
+        self
+    }
+
+    pub fn tagged(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+}
+
+/// Applies the modification to the agent with the given id, if it still
+/// exists.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ModifyAgent<MODIFY>(pub AgentId, pub MODIFY);
+
+/// The phases `Request::phase` orders requests into, in order.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Remove,
+    Modify,
+    Create,
+    Custom,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Request<AGENT, CUSTOM = Infallible, MODIFY = Infallible> {
+    Create(CreateAgent<AGENT>),
+    Remove(RemoveAgent),
+    Modify(ModifyAgent<MODIFY>),
+    Custom(CUSTOM),
+}
+
+impl<AGENT, CUSTOM, MODIFY> Request<AGENT, CUSTOM, MODIFY> {
+    pub fn phase(&self) -> Phase {
+        match self {
+            Request::Remove(_) => Phase::Remove,
+            Request::Modify(_) => Phase::Modify,
+            Request::Create(_) => Phase::Create,
+            Request::Custom(_) => Phase::Custom,
+        }
+    }
+
+    pub fn phase_and_target(&self) -> (Phase, Option<AgentId>) {
+        let target = match self {
+            Request::Remove(RemoveAgent(id)) | Request::Modify(ModifyAgent(id, _)) => Some(*id),
+            Request::Create(_) | Request::Custom(_) => None,
+        };
+        (self.phase(), target)
+    }
+}
+
+impl<AGENT, CUSTOM, MODIFY> From<CreateAgent<AGENT>> for Request<AGENT, CUSTOM, MODIFY> {
+    fn from(request: CreateAgent<AGENT>) -> Self {
+        Request::Create(request)
+    }
+}
+
+impl<AGENT, CUSTOM, MODIFY> From<RemoveAgent> for Request<AGENT, CUSTOM, MODIFY> {
+    fn from(request: RemoveAgent) -> Self {
+        Request::Remove(request)
+    }
+}
+
+impl<AGENT, CUSTOM, MODIFY> From<ModifyAgent<MODIFY>> for Request<AGENT, CUSTOM, MODIFY> {
+    fn from(request: ModifyAgent<MODIFY>) -> Self {
+        Request::Modify(request)
+    }
+}
+
+/// The simulation specific part of applying `Request`s.
+///
+/// `agent_created` and `agent_removed` are called after the standard
+/// requests are applied, and the agent's names registered or unregistered,
+/// so contexts can log them or keep other state in step.
+pub trait RequestHandler<AGENT, CUSTOM, MODIFY = Infallible> {
+    fn apply_custom_request(&mut self, request: CUSTOM);
+    /// Applies `modification` to the agent with id `id`, which exists.
+    fn apply_modification(&mut self, id: AgentId, modification: MODIFY);
+    fn agent_created(&mut self, _id: AgentId) {}
+    fn agent_removed(&mut self, _id: AgentId, _agent: AGENT) {}
+    /// A created agent asked for a name another agent already has. The
+    /// agent is created without it, and by default the name stays with its
+    /// owner.
+    fn name_taken(&mut self, _id: AgentId, _taken: NameTaken) {}
+
+    /// Applies all of a step's requests, in order, with `apply_request`.
+    /// This is what `System::apply_system_requests` calls, so override it
+    /// to handle the batch as a whole, e.g. to apply all the removals at
+    /// once.
+    fn apply_requests(&mut self, requests: Vec<Request<AGENT, CUSTOM, MODIFY>>)
+    where
+        Self: MapContext<AGENT> + IdContext + NamedContext,
+        AGENT: SetIdOp,
+    {
+        for request in requests {
+            apply_request(self, request);
+        }
+    }
+}
+
+/// Applies a single request the standard way, as
+/// `RequestHandler::apply_requests` does for each request by default.
+pub fn apply_request<T, AGENT, CUSTOM, MODIFY>(
+    context: &mut T,
+    request: Request<AGENT, CUSTOM, MODIFY>,
+) where
+    T: MapContext<AGENT>
+        + IdContext
+        + NamedContext
+        + RequestHandler<AGENT, CUSTOM, MODIFY>
+        + ?Sized,
+    AGENT: SetIdOp,
+{
+    match request {
+        Request::Create(CreateAgent { agent, names, tags }) => {
+            let id = context.insert_agent(agent);
+            for name in names {
+                if let Err(taken) = context.names_mut().register(id, &name) {
+                    context.name_taken(id, taken);
+                }
+            }
+            for tag in tags {
+                context.names_mut().tag(id, &tag);
+            }
+            context.agent_created(id);
+        }
+        Request::Remove(RemoveAgent(id)) => {
+            if let Some(agent) = context.destroy_agent(id) {
+                context.forget_agent(id);
+                context.agent_removed(id, agent);
+            }
+        }
+        Request::Modify(ModifyAgent(id, modification)) => {
+            if context.agents().contains_key(&id) {
+                context.apply_modification(id, modification);
+            }
+        }
+        Request::Custom(request) => context.apply_custom_request(request),
+    }
+}
+
+impl<T, AGENT, CUSTOM, MODIFY> System<Request<AGENT, CUSTOM, MODIFY>> for T
+where
+    T: MapContext<AGENT> + IdContext + NamedContext + RequestHandler<AGENT, CUSTOM, MODIFY>,
+    AGENT: SetIdOp,
+{
+    type AgentType = AGENT;
+
+    fn apply_system_request(&mut self, request: Request<AGENT, CUSTOM, MODIFY>) {
+        apply_request(self, request);
+    }
+
+    fn apply_system_requests(&mut self, requests: Vec<Request<AGENT, CUSTOM, MODIFY>>) {
+        self.apply_requests(requests);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::ids::IdAllocator;
+    use crate::names::{NameRegistry, NameResolver, NamedContext};
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    struct Counter {
+        id: AgentId,
+        count: u32,
+    }
+
+    #[derive(Default)]
+    struct Context {
+        agents: BTreeMap<AgentId, Counter>,
+        ids: IdAllocator,
+        names: NameRegistry,
+        removed: Vec<Counter>,
+        resets: u32,
+        batches: u32,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    struct ResetAll;
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    struct Add(u32);
+
+    impl SetIdOp for Counter {
+        fn set_id(&mut self, id: AgentId) {
+            self.id = id;
+        }
+    }
+
+    impl MapContext<Counter> for Context {
+        fn agents(&self) -> &BTreeMap<AgentId, Counter> {
+            &self.agents
+        }
+        fn agents_mut(&mut self) -> &mut BTreeMap<AgentId, Counter> {
+            &mut self.agents
+        }
+    }
+
+    impl NamedContext for Context {
+        fn names(&self) -> &NameRegistry {
+            &self.names
+        }
+        fn names_mut(&mut self) -> &mut NameRegistry {
+            &mut self.names
+        }
+    }
+
+    impl IdContext for Context {
+        fn ids(&self) -> &IdAllocator {
+            &self.ids
+        }
+        fn ids_mut(&mut self) -> &mut IdAllocator {
+            &mut self.ids
+        }
+    }
+
+    impl RequestHandler<Counter, ResetAll, Add> for Context {
+        fn apply_custom_request(&mut self, _request: ResetAll) {
+            self.resets += 1;
+            for agent in self.agents.values_mut() {
+                agent.count = 0;
+            }
+        }
+        fn apply_modification(&mut self, id: AgentId, Add(n): Add) {
+            self.agents.get_mut(&id).unwrap().count += n;
+        }
+        fn agent_removed(&mut self, _id: AgentId, agent: Counter) {
+            self.removed.push(agent);
+        }
+        fn apply_requests(&mut self, requests: Vec<Request<Counter, ResetAll, Add>>) {
+            self.batches += 1;
+            for request in requests {
+                apply_request(self, request);
+            }
+        }
+    }
+
+    #[test]
+    fn test_standard_requests() {
+        let mut context = Context::default();
+        // The ids the agents are created with are replaced.
+        let unset = AgentId(u64::MAX);
+        let requests: Vec<Request<Counter, ResetAll, Add>> = vec![
+            CreateAgent::new(Counter {
+                id: unset,
+                count: 1,
+            })
+            .into(),
+            CreateAgent::new(Counter {
+                id: unset,
+                count: 2,
+            })
+            .into(),
+            ModifyAgent(AgentId(1), Add(10)).into(),
+            RemoveAgent(AgentId(0)).into(),
+            // The agent has gone, so these do nothing.
+            RemoveAgent(AgentId(0)).into(),
+            ModifyAgent(AgentId(0), Add(10)).into(),
+        ];
+        assert_eq!(requests[2], ModifyAgent(AgentId(1), Add(10)).into());
+        // The batch goes through the context's own apply_requests.
+        context.apply_system_requests(requests);
+        assert_eq!(context.batches, 1);
+
+        assert_eq!(
+            context.agents.values().collect::<Vec<_>>(),
+            vec![&Counter {
+                id: AgentId(1),
+                count: 12
+            }]
+        );
+        assert_eq!(
+            context.removed,
+            vec![Counter {
+                id: AgentId(0),
+                count: 1
+            }]
+        );
+
+        context.apply_system_request(Request::Custom(ResetAll));
+        assert_eq!(context.resets, 1);
+        assert_eq!(context.agents[&AgentId(1)].count, 0);
+    }
+
+    #[test]
+    fn test_phase_and_target() {
+        let mut requests: Vec<Request<Counter, ResetAll, Add>> = vec![
+            Request::Custom(ResetAll),
+            ModifyAgent(AgentId(2), Add(1)).into(),
+            RemoveAgent(AgentId(3)).into(),
+            ModifyAgent(AgentId(1), Add(1)).into(),
+            RemoveAgent(AgentId(0)).into(),
+        ];
+        requests.sort_by_key(Request::phase_and_target);
+        assert_eq!(
+            requests,
+            vec![
+                RemoveAgent(AgentId(0)).into(),
+                RemoveAgent(AgentId(3)).into(),
+                ModifyAgent(AgentId(1), Add(1)).into(),
+                ModifyAgent(AgentId(2), Add(1)).into(),
+                Request::Custom(ResetAll),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_named_agents() {
+        let mut context = Context::default();
+        let counter = Counter {
+            id: AgentId(0),
+            count: 0,
+        };
+        let requests: Vec<Request<Counter, ResetAll, Add>> = vec![
+            CreateAgent::new(counter.clone())
+                .named("counter")
+                .tagged("counters")
+                .into(),
+            // The name is taken, so only the tag is registered.
+            CreateAgent::new(counter)
+                .named("counter")
+                .tagged("counters")
+                .into(),
+        ];
+        for request in requests {
+            context.apply_system_request(request);
+        }
+        assert_eq!(context.resolve_id_from_name("counter"), Some(AgentId(0)));
+        assert_eq!(context.names.tagged("counters").count(), 2);
+
+        context.apply_system_request(Request::<Counter, ResetAll, Add>::from(RemoveAgent(
+            AgentId(0),
+        )));
+
+        assert_eq!(context.agents.len(), 1);
+        assert_eq!(context.resolve_id_from_name("counter"), None);
+        assert_eq!(
+            context.names.tagged("counters").collect::<Vec<_>>(),
+            vec![AgentId(1)]
+        );
+    }
+
+    #[test]
+    fn test_requests_are_data() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Request<Counter, ResetAll, Add>>();
+
+        // So the library policies work with them.
+        use crate::conflicts::{dedupe, Pending, RequestPolicy, Verdict};
+        let request = Pending {
+            from: AgentId(0),
+            request: Request::<Counter, ResetAll, Add>::from(RemoveAgent(AgentId(1))),
+        };
+        let mut policy = dedupe();
+        RequestPolicy::<Context, _>::accept(&mut policy, &request);
+        assert_eq!(policy.check(&Context::default(), &request), Verdict::Drop);
+    }
+}
@@ -0,0 +1,169 @@
+/// Creates a composite behaviour that applies any number of behaviours
+/// in order, each acting on the result of the previous one.
+///
+/// This is the N-ary version of `Chain`: `Sequence((a, b, c))` behaves like
+/// `Chain::chain(Chain::chain(a, b), c)` but keeps the type flat, which
+/// makes error messages much shorter. It is usually built with the
+/// `sequence!` macro. Sequences of up to 12 stages are supported (nest
+/// them if you need more).
+///
+/// As with `Chain`, each stage is statically dispatched.
+///
+/// To see which stage produced a given state use `act_traced`, which
+/// reports the index and type name of each stage along with the state it
+/// produced.
+use crate::behaviour::Behaviour;
+use crate::behaviour_ext::BehaviourExt;
+use crate::behaviour_mut::BehaviourMut;
+
+pub struct Sequence<T>(pub T);
+
+impl<T> BehaviourExt for Sequence<T> {}
+
+impl<T> Sequence<T> {
+    pub fn new(stages: T) -> Self {
+        Sequence(stages)
+    }
+}
+
+/// Builds a `Sequence` from a list of behaviours.
+///
+/// `sequence!(a, b, c)` is shorthand for `Sequence::new((a, b, c))`.
+#[macro_export]
+macro_rules! sequence {
+    ($($stage:expr),+ $(,)?) => {
+        $crate::sequence::Sequence::new(($($stage,)+))
+    };
+}
+
+macro_rules! impl_sequence {
+    ($first:ident $first_idx:tt $(, $name:ident $idx:tt)*) => {
+        impl<$first $(, $name)*> Sequence<($first, $($name,)*)> {
+            /// Applies each stage in turn, calling `trace` with the index
+            /// and type name of the stage and the state it produced.
+            pub fn act_traced<STATE, CONTEXT, TRACE>(
+                &self,
+                state: &STATE,
+                context: &CONTEXT,
+                mut trace: TRACE,
+            ) -> STATE
+            where
+                $first: Behaviour<STATE, CONTEXT>,
+                $($name: Behaviour<STATE, CONTEXT>,)*
+                TRACE: FnMut(usize, &'static str, &STATE),
+            {
+                #[allow(unused_mut)]
+                let mut current = (self.0).$first_idx.act(state, context);
+                trace($first_idx, std::any::type_name::<$first>(), &current);
+                $(
+                    current = (self.0).$idx.act(&current, context);
+                    trace($idx, std::any::type_name::<$name>(), &current);
+                )*
+                current
+            }
+        }
+
+        impl<STATE, CONTEXT, $first $(, $name)*> Behaviour<STATE, CONTEXT>
+            for Sequence<($first, $($name,)*)>
+        where
+            $first: Behaviour<STATE, CONTEXT>,
+            $($name: Behaviour<STATE, CONTEXT>,)*
+        {
+            fn act(&self, state: &STATE, context: &CONTEXT) -> STATE {
+                self.act_traced(state, context, |_, _, _| {})
+            }
+
+            fn act_changed(&self, state: &STATE, context: &CONTEXT) -> Option<STATE> {
+                #[allow(unused_mut)]
+                let mut current = (self.0).$first_idx.act_changed(state, context);
+                $(
+                    let next = match &current {
+                        Some(current) => (self.0).$idx.act_changed(current, context),
+                        None => (self.0).$idx.act_changed(state, context),
+                    };
+                    if next.is_some() {
+                        current = next;
+                    }
+                )*
+                current
+            }
+        }
+
+        impl<STATE, CONTEXT, $first $(, $name)*> BehaviourMut<STATE, CONTEXT>
+            for Sequence<($first, $($name,)*)>
+        where
+            $first: BehaviourMut<STATE, CONTEXT>,
+            $($name: BehaviourMut<STATE, CONTEXT>,)*
+        {
+            fn act_mut(&self, state: &mut STATE, context: &CONTEXT) {
+                (self.0).$first_idx.act_mut(state, context);
+                $((self.0).$idx.act_mut(state, context);)*
+            }
+        }
+    };
+}
+
+impl_sequence!(A 0);
+impl_sequence!(A 0, B 1);
+impl_sequence!(A 0, B 1, C 2);
+impl_sequence!(A 0, B 1, C 2, D 3);
+impl_sequence!(A 0, B 1, C 2, D 3, E 4);
+impl_sequence!(A 0, B 1, C 2, D 3, E 4, F 5);
+impl_sequence!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_sequence!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+impl_sequence!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8);
+impl_sequence!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9);
+impl_sequence!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10);
+impl_sequence!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11);
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    struct Add(u32);
+
+    impl Behaviour<u32, ()> for Add {
+        fn act(&self, state: &u32, _context: &()) -> u32 {
+            state + self.0
+        }
+    }
+
+    struct Double {}
+
+    impl Behaviour<u32, ()> for Double {
+        fn act(&self, state: &u32, _context: &()) -> u32 {
+            state * 2
+        }
+    }
+
+    #[test]
+    fn test_sequence() {
+        let b = sequence!(Add(1), Double {}, Add(3), Double {});
+
+        let _m: &dyn Behaviour<u32, ()> = &b;
+
+        assert_eq!(b.act(&1, &()), 14);
+        assert_eq!(b.act_changed(&1, &()), Some(14));
+    }
+
+    #[test]
+    fn test_act_traced() {
+        let b = sequence!(Add(1), Double {}, Add(3));
+
+        let mut trace = vec![];
+        let result = b.act_traced(&1, &(), |index, name, state: &u32| {
+            trace.push((index, name, *state))
+        });
+
+        assert_eq!(result, 7);
+        assert_eq!(
+            trace,
+            vec![
+                (0, std::any::type_name::<Add>(), 2),
+                (1, std::any::type_name::<Double>(), 4),
+                (2, std::any::type_name::<Add>(), 7),
+            ]
+        );
+    }
+}
@@ -0,0 +1,320 @@
+/// A driver that owns a context and runs the step lifecycle.
+///
+/// Each call to `step` runs these phases, in this order:
+/// 1. every agent acts on the context as it was at the start of the step
+///    (see `utils::step_agents`),
+/// 2. the messages the agents sent are gathered from their outboxes,
+/// 3. the system requests in the agents' system outboxes are applied, in
+///    the simulation's `RequestOrder` (see
+///    `utils::perform_ordered_system_actions`), so agents are created and
+///    removed. A simulation set up `with_conflict_policy` only applies
+///    the requests its `RequestPolicy` accepts (see
+///    `conflicts::perform_checked_system_actions`),
+/// 4. the gathered messages are delivered.
+///
+/// Phases 2 and 4 only run for simulations set up `with_messages`, whose
+/// context is a `MessageSystem`. Messages are delivered to the agents that
+/// exist after the system requests are applied, and are seen in the
+/// agents' inboxes in the next step.
+///
+/// Everything that carries over between steps, such as messages still in
+/// flight, lives in the context, so `snapshot` and `restore` save and
+/// roll back the whole simulation.
+use std::marker::PhantomData;
+
+use crate::behaviour::Behaviour;
+use crate::conflicts::{perform_checked_system_actions, RejectionsOp, RequestPolicy};
+use crate::map_context::MapContext;
+use crate::utils::{
+    perform_ordered_system_actions, step_agents, AgentBase, AgentsMut, BaseOp, MessageSystem,
+    RequestOrder, System, Unordered,
+};
+
+type Types<AGENT, REQUEST> = PhantomData<fn() -> (AGENT, REQUEST)>;
+
+/// The messaging phases of a step: what is gathered before the system
+/// requests are applied is delivered after.
+pub trait Messaging<CONTEXT> {
+    type InFlight;
+    fn gather(&self, context: &mut CONTEXT) -> Self::InFlight;
+    fn deliver(&self, context: &mut CONTEXT, messages: Self::InFlight);
+}
+
+/// A simulation without messaging.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoMessages;
+
+impl<CONTEXT> Messaging<CONTEXT> for NoMessages {
+    type InFlight = ();
+    fn gather(&self, _context: &mut CONTEXT) {}
+    fn deliver(&self, _context: &mut CONTEXT, _messages: ()) {}
+}
+
+/// Moves messages of type `MESSAGE` with the context's `MessageSystem`.
+pub struct Messages<MESSAGE>(PhantomData<fn() -> MESSAGE>);
+
+impl<CONTEXT, MESSAGE> Messaging<CONTEXT> for Messages<MESSAGE>
+where
+    CONTEXT: MessageSystem<MESSAGE>,
+{
+    type InFlight = Vec<MESSAGE>;
+    fn gather(&self, context: &mut CONTEXT) -> Vec<MESSAGE> {
+        context.gather_messages()
+    }
+    fn deliver(&self, context: &mut CONTEXT, messages: Vec<MESSAGE>) {
+        context.deliver_messages(messages)
+    }
+}
+
+/// The system request phase of a step: which of the agents' requests are
+/// applied.
+pub trait RequestPhase<CONTEXT, REQUEST> {
+    fn apply<O>(&mut self, context: &mut CONTEXT, order: &O)
+    where
+        O: RequestOrder<REQUEST>;
+}
+
+/// Applies every request.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Unchecked;
+
+impl<CONTEXT, REQUEST> RequestPhase<CONTEXT, REQUEST> for Unchecked
+where
+    CONTEXT: System<REQUEST> + AgentsMut<<CONTEXT as System<REQUEST>>::AgentType>,
+    CONTEXT::AgentType: AgentBase<REQUEST>,
+{
+    fn apply<O>(&mut self, context: &mut CONTEXT, order: &O)
+    where
+        O: RequestOrder<REQUEST>,
+    {
+        perform_ordered_system_actions(context, order);
+    }
+}
+
+/// Applies the requests the policy `P` accepts. The rejected ones are
+/// handed back to the agents that made them, through `RejectionsOp`.
+pub struct Checked<P>(P);
+
+impl<CONTEXT, REQUEST, P> RequestPhase<CONTEXT, REQUEST> for Checked<P>
+where
+    CONTEXT: System<REQUEST> + AgentsMut<<CONTEXT as System<REQUEST>>::AgentType>,
+    CONTEXT::AgentType: AgentBase<REQUEST> + BaseOp + RejectionsOp<REQUEST>,
+    P: RequestPolicy<CONTEXT, REQUEST>,
+    REQUEST: Clone,
+{
+    fn apply<O>(&mut self, context: &mut CONTEXT, order: &O)
+    where
+        O: RequestOrder<REQUEST>,
+    {
+        perform_checked_system_actions(context, order, &mut self.0);
+    }
+}
+
+/// `run_until_or` ran out of steps before its predicate held.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StepLimit(pub u64);
+
+/// A copy of a simulation's context and step count.
+#[derive(Debug, Clone)]
+pub struct Snapshot<CONTEXT> {
+    pub steps: u64,
+    pub context: CONTEXT,
+}
+
+pub struct Simulation<
+    B,
+    CONTEXT,
+    AGENT,
+    REQUEST,
+    ORDER = Unordered,
+    MESSAGING = NoMessages,
+    REQUESTS = Unchecked,
+> {
+    behaviour: B,
+    context: CONTEXT,
+    order: ORDER,
+    messaging: MESSAGING,
+    requests: REQUESTS,
+    steps: u64,
+    _types: Types<AGENT, REQUEST>,
+}
+
+impl<B, CONTEXT, AGENT, REQUEST> Simulation<B, CONTEXT, AGENT, REQUEST>
+where
+    B: Behaviour<AGENT, CONTEXT>,
+    CONTEXT: MapContext<AGENT> + System<REQUEST, AgentType = AGENT>,
+    AGENT: AgentBase<REQUEST>,
+{
+    /// A simulation without messaging, that applies system requests in
+    /// the order they were made.
+    pub fn new(behaviour: B, context: CONTEXT) -> Self {
+        Simulation {
+            behaviour,
+            context,
+            order: Unordered,
+            messaging: NoMessages,
+            requests: Unchecked,
+            steps: 0,
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<B, CONTEXT, AGENT, REQUEST, ORDER, MESSAGING, REQUESTS>
+    Simulation<B, CONTEXT, AGENT, REQUEST, ORDER, MESSAGING, REQUESTS>
+where
+    B: Behaviour<AGENT, CONTEXT>,
+    CONTEXT: MapContext<AGENT> + System<REQUEST, AgentType = AGENT>,
+    AGENT: AgentBase<REQUEST>,
+    ORDER: RequestOrder<REQUEST>,
+    MESSAGING: Messaging<CONTEXT>,
+    REQUESTS: RequestPhase<CONTEXT, REQUEST>,
+{
+    /// Applies system requests in `order` from now on.
+    pub fn with_request_order<O>(
+        self,
+        order: O,
+    ) -> Simulation<B, CONTEXT, AGENT, REQUEST, O, MESSAGING, REQUESTS>
+    where
+        O: RequestOrder<REQUEST>,
+    {
+        Simulation {
+            behaviour: self.behaviour,
+            context: self.context,
+            order,
+            messaging: self.messaging,
+            requests: self.requests,
+            steps: self.steps,
+            _types: PhantomData,
+        }
+    }
+
+    /// Moves messages of type `MESSAGE` between the agents each step from
+    /// now on.
+    pub fn with_messages<MESSAGE>(
+        self,
+    ) -> Simulation<B, CONTEXT, AGENT, REQUEST, ORDER, Messages<MESSAGE>, REQUESTS>
+    where
+        CONTEXT: MessageSystem<MESSAGE>,
+    {
+        Simulation {
+            behaviour: self.behaviour,
+            context: self.context,
+            order: self.order,
+            messaging: Messages(PhantomData),
+            requests: self.requests,
+            steps: self.steps,
+            _types: PhantomData,
+        }
+    }
+
+    /// Only applies the system requests `policy` accepts from now on, see
+    /// `conflicts::perform_checked_system_actions`. The agents need to
+    /// implement `RejectionsOp` to be told which were rejected.
+    pub fn with_conflict_policy<P>(
+        self,
+        policy: P,
+    ) -> Simulation<B, CONTEXT, AGENT, REQUEST, ORDER, MESSAGING, Checked<P>>
+    where
+        Checked<P>: RequestPhase<CONTEXT, REQUEST>,
+    {
+        Simulation {
+            behaviour: self.behaviour,
+            context: self.context,
+            order: self.order,
+            messaging: self.messaging,
+            requests: Checked(policy),
+            steps: self.steps,
+            _types: PhantomData,
+        }
+    }
+
+    /// Runs a single step.
+    pub fn step(&mut self) {
+        step_agents(&self.behaviour, &mut self.context);
+        let messages = self.messaging.gather(&mut self.context);
+        self.requests.apply(&mut self.context, &self.order);
+        self.messaging.deliver(&mut self.context, messages);
+        self.steps += 1;
+    }
+
+    /// Runs `n` steps.
+    pub fn run(&mut self, n: u64) {
+        for _ in 0..n {
+            self.step();
+        }
+    }
+
+    /// Runs steps until `predicate` holds for the context, checking it
+    /// before each step, and returns the number of steps run.
+    ///
+    /// This never returns if the predicate never holds; `run_until_or`
+    /// gives up after a number of steps.
+    pub fn run_until<P>(&mut self, mut predicate: P) -> u64
+    where
+        P: FnMut(&CONTEXT) -> bool,
+    {
+        let start = self.steps;
+        while !predicate(&self.context) {
+            self.step();
+        }
+        self.steps - start
+    }
+
+    /// Runs steps until `predicate` holds for the context, as `run_until`,
+    /// but runs at most `max_steps` steps, and returns `Err` if the
+    /// predicate still doesn't hold after them.
+    pub fn run_until_or<P>(&mut self, max_steps: u64, mut predicate: P) -> Result<u64, StepLimit>
+    where
+        P: FnMut(&CONTEXT) -> bool,
+    {
+        for steps in 0..max_steps {
+            if predicate(&self.context) {
+                return Ok(steps);
+            }
+            self.step();
+        }
+        if predicate(&self.context) {
+            Ok(max_steps)
+        } else {
+            Err(StepLimit(max_steps))
+        }
+    }
+}
+
+impl<B, CONTEXT, AGENT, REQUEST, ORDER, MESSAGING, REQUESTS>
+    Simulation<B, CONTEXT, AGENT, REQUEST, ORDER, MESSAGING, REQUESTS>
+{
+    /// The number of steps run so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn context(&self) -> &CONTEXT {
+        &self.context
+    }
+
+    pub fn context_mut(&mut self) -> &mut CONTEXT {
+        &mut self.context
+    }
+
+    pub fn into_context(self) -> CONTEXT {
+        self.context
+    }
+
+    pub fn snapshot(&self) -> Snapshot<CONTEXT>
+    where
+        CONTEXT: Clone,
+    {
+        Snapshot {
+            steps: self.steps,
+            context: self.context.clone(),
+        }
+    }
+
+    /// Rolls the simulation back to `snapshot`.
+    pub fn restore(&mut self, snapshot: Snapshot<CONTEXT>) {
+        self.steps = snapshot.steps;
+        self.context = snapshot.context;
+    }
+}
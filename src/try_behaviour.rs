@@ -0,0 +1,192 @@
+/// Behaviours that can fail.
+///
+/// A `TryBehaviour` returns `Result<STATE, Self::Error>` rather than a bare
+/// `STATE`, so problems like a missing neighbour or a degenerate vector can
+/// be reported instead of hidden or turned into a panic.
+///
+/// `TryChain` and `TryActMapIf` are the fallible versions of `Chain` and
+/// `ActMapIf`, and stop at the first error. `always_ok` lifts an ordinary
+/// `Behaviour` into a `TryBehaviour` so it can be mixed in with these.
+///
+/// `utils::try_step_agents` steps a whole context and applies an
+/// `ErrorPolicy` to the agents whose behaviour failed.
+use std::marker::PhantomData;
+
+use crate::act_map_if::TryIntoResult;
+use crate::behaviour::Behaviour;
+use crate::utils::AgentId;
+
+pub trait TryBehaviour<STATE, CONTEXT> {
+    type Error;
+    fn try_act(&self, state: &STATE, context: &CONTEXT) -> Result<STATE, Self::Error>;
+}
+
+/// What `try_step_agents` should do with the agents whose behaviour failed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Leave every agent as it was before the step.
+    AbortStep,
+    /// Update the agents that succeeded, and leave the failed ones as they
+    /// were. To remove the failed agents instead, pass the returned errors
+    /// to `utils::remove_failed_agents`.
+    KeepPrevious,
+}
+
+/// An error produced by the behaviour of a single agent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgentError<E> {
+    pub id: AgentId,
+    pub error: E,
+}
+
+/// The result of `try_step_agents`: the errors of the failed agents, as
+/// `Err` if the step was aborted and `Ok` otherwise.
+pub type StepResult<E> = Result<Vec<AgentError<E>>, Vec<AgentError<E>>>;
+
+/// Wraps a `Behaviour` that can't fail as a `TryBehaviour` with any
+/// error type.
+pub struct AlwaysOk<B, E> {
+    b: B,
+    _error: PhantomData<fn() -> E>,
+}
+
+pub fn always_ok<B, E>(b: B) -> AlwaysOk<B, E> {
+    AlwaysOk {
+        b,
+        _error: PhantomData,
+    }
+}
+
+impl<B, E, STATE, CONTEXT> TryBehaviour<STATE, CONTEXT> for AlwaysOk<B, E>
+where
+    B: Behaviour<STATE, CONTEXT>,
+{
+    type Error = E;
+    fn try_act(&self, state: &STATE, context: &CONTEXT) -> Result<STATE, E> {
+        Ok(self.b.act(state, context))
+    }
+}
+
+/// Applies the first behaviour and then the second to its result,
+/// stopping at the first error.
+pub struct TryChain<A, B> {
+    a: A,
+    b: B,
+}
+
+impl<A, B> TryChain<A, B> {
+    pub fn new(a: A, b: B) -> Self {
+        TryChain { a, b }
+    }
+}
+
+impl<STATE, CONTEXT, A, B> TryBehaviour<STATE, CONTEXT> for TryChain<A, B>
+where
+    A: TryBehaviour<STATE, CONTEXT>,
+    B: TryBehaviour<STATE, CONTEXT, Error = A::Error>,
+{
+    type Error = A::Error;
+    fn try_act(&self, state: &STATE, context: &CONTEXT) -> Result<STATE, A::Error> {
+        let temp_state = self.a.try_act(state, context)?;
+        self.b.try_act(&temp_state, context)
+    }
+}
+
+/// The fallible version of `ActMapIf`. States that don't match are passed
+/// through as `Ok`.
+pub struct TryActMapIf<A, F> {
+    a: A,
+    f: F,
+}
+
+pub fn try_act_map_if<A, F>(f: F, a: A) -> TryActMapIf<A, F> {
+    TryActMapIf { a, f }
+}
+
+impl<A, F, CHILD, STATE, CONTEXT> TryBehaviour<STATE, CONTEXT> for TryActMapIf<A, F>
+where
+    STATE: Clone,
+    F: Fn(STATE) -> TryIntoResult<CHILD, STATE>,
+    A: TryBehaviour<CHILD, CONTEXT>,
+    CHILD: Into<STATE>,
+{
+    type Error = A::Error;
+    fn try_act(&self, state: &STATE, context: &CONTEXT) -> Result<STATE, A::Error> {
+        match (self.f)(state.clone()) {
+            TryIntoResult::Ok(child) => Ok(self.a.try_act(&child, context)?.into()),
+            TryIntoResult::Failed(state) => Ok(state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct TooBig(u32);
+
+    struct CheckedIncrement {
+        limit: u32,
+    }
+
+    impl TryBehaviour<u32, ()> for CheckedIncrement {
+        type Error = TooBig;
+        fn try_act(&self, state: &u32, _context: &()) -> Result<u32, TooBig> {
+            if *state >= self.limit {
+                Err(TooBig(*state))
+            } else {
+                Ok(state + 1)
+            }
+        }
+    }
+
+    struct Double {}
+
+    impl Behaviour<u32, ()> for Double {
+        fn act(&self, state: &u32, _context: &()) -> u32 {
+            state * 2
+        }
+    }
+
+    #[test]
+    fn test_try_chain_short_circuits() {
+        let b = TryChain::new(always_ok(Double {}), CheckedIncrement { limit: 10 });
+
+        let _m: &dyn TryBehaviour<u32, (), Error = TooBig> = &b;
+
+        assert_eq!(b.try_act(&3, &()), Ok(7));
+        assert_eq!(b.try_act(&6, &()), Err(TooBig(12)));
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum AB {
+        A(u32),
+        B(u32),
+    }
+
+    impl AB {
+        fn try_as_a(self) -> TryIntoResult<u32, AB> {
+            match self {
+                AB::A(a) => TryIntoResult::Ok(a),
+                AB::B(_) => TryIntoResult::Failed(self),
+            }
+        }
+    }
+
+    impl From<u32> for AB {
+        fn from(a: u32) -> AB {
+            AB::A(a)
+        }
+    }
+
+    #[test]
+    fn test_try_act_map_if() {
+        let b = try_act_map_if(|x: AB| x.try_as_a(), CheckedIncrement { limit: 10 });
+
+        assert_eq!(b.try_act(&AB::A(3), &()), Ok(AB::A(4)));
+        assert_eq!(b.try_act(&AB::A(10), &()), Err(TooBig(10)));
+        assert_eq!(b.try_act(&AB::B(10), &()), Ok(AB::B(10)));
+    }
+}
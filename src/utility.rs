@@ -0,0 +1,319 @@
+/// Utility-AI selection: choose one of several behaviours each step based
+/// on how useful each one looks for the current state and context.
+///
+/// Each option is a (scorer, behaviour) pair built with `scored`, where the
+/// scorer is a `Fn(&STATE, &CONTEXT) -> f32`. The options are given as a
+/// tuple (of up to 12 options) and statically dispatched, as in
+/// `sequence::Sequence`. Only the chosen behaviour is applied.
+///
+/// * `highest_score(options)` applies the option with the highest score
+///   (the first one on a tie).
+/// * `weighted_random(options)` picks an option at random with probability
+///   proportional to its score, using the random number generator of the
+///   context (see `RngContext`). Options scoring zero or less are never
+///   picked, and if every option does the state is left unchanged.
+use rand::{Rng, RngCore};
+
+use crate::behaviour::Behaviour;
+use crate::behaviour_ext::BehaviourExt;
+
+/// The most options a utility selector can have.
+const MAX_OPTIONS: usize = 12;
+
+/// A context that provides a random number generator.
+///
+/// Behaviours only get `&CONTEXT`, so implementations usually keep a
+/// seeded generator in a `RefCell`. Agents are stepped in id order, so a
+/// seeded generator gives reproducible runs.
+pub trait RngContext {
+    fn with_rng<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut dyn RngCore) -> T;
+}
+
+pub struct Scored<S, B> {
+    scorer: S,
+    behaviour: B,
+}
+
+pub fn scored<S, B, STATE, CONTEXT>(scorer: S, behaviour: B) -> Scored<S, B>
+where
+    S: Fn(&STATE, &CONTEXT) -> f32,
+{
+    Scored { scorer, behaviour }
+}
+
+/// A tuple of scored options.
+pub trait UtilityOptions<STATE, CONTEXT> {
+    const COUNT: usize;
+    fn score(&self, index: usize, state: &STATE, context: &CONTEXT) -> f32;
+    fn act_option(&self, index: usize, state: &STATE, context: &CONTEXT) -> STATE;
+    fn act_changed_option(&self, index: usize, state: &STATE, context: &CONTEXT) -> Option<STATE>;
+}
+
+macro_rules! impl_options {
+    ($n:literal; $($s:ident $b:ident $idx:tt),+) => {
+        impl<STATE, CONTEXT, $($s, $b),+> UtilityOptions<STATE, CONTEXT>
+            for ($(Scored<$s, $b>,)+)
+        where
+            $($s: Fn(&STATE, &CONTEXT) -> f32, $b: Behaviour<STATE, CONTEXT>,)+
+        {
+            const COUNT: usize = $n;
+
+            fn score(&self, index: usize, state: &STATE, context: &CONTEXT) -> f32 {
+                match index {
+                    $($idx => (self.$idx.scorer)(state, context),)+
+                    _ => panic!("utility option {} out of range", index),
+                }
+            }
+
+            fn act_option(&self, index: usize, state: &STATE, context: &CONTEXT) -> STATE {
+                match index {
+                    $($idx => self.$idx.behaviour.act(state, context),)+
+                    _ => panic!("utility option {} out of range", index),
+                }
+            }
+
+            fn act_changed_option(
+                &self,
+                index: usize,
+                state: &STATE,
+                context: &CONTEXT,
+            ) -> Option<STATE> {
+                match index {
+                    $($idx => self.$idx.behaviour.act_changed(state, context),)+
+                    _ => panic!("utility option {} out of range", index),
+                }
+            }
+        }
+    };
+}
+
+impl_options!(1; S0 B0 0);
+impl_options!(2; S0 B0 0, S1 B1 1);
+impl_options!(3; S0 B0 0, S1 B1 1, S2 B2 2);
+impl_options!(4; S0 B0 0, S1 B1 1, S2 B2 2, S3 B3 3);
+impl_options!(5; S0 B0 0, S1 B1 1, S2 B2 2, S3 B3 3, S4 B4 4);
+impl_options!(6; S0 B0 0, S1 B1 1, S2 B2 2, S3 B3 3, S4 B4 4, S5 B5 5);
+impl_options!(7; S0 B0 0, S1 B1 1, S2 B2 2, S3 B3 3, S4 B4 4, S5 B5 5, S6 B6 6);
+impl_options!(8; S0 B0 0, S1 B1 1, S2 B2 2, S3 B3 3, S4 B4 4, S5 B5 5, S6 B6 6, S7 B7 7);
+impl_options!(9; S0 B0 0, S1 B1 1, S2 B2 2, S3 B3 3, S4 B4 4, S5 B5 5, S6 B6 6, S7 B7 7,
+    S8 B8 8);
+impl_options!(10; S0 B0 0, S1 B1 1, S2 B2 2, S3 B3 3, S4 B4 4, S5 B5 5, S6 B6 6, S7 B7 7,
+    S8 B8 8, S9 B9 9);
+impl_options!(11; S0 B0 0, S1 B1 1, S2 B2 2, S3 B3 3, S4 B4 4, S5 B5 5, S6 B6 6, S7 B7 7,
+    S8 B8 8, S9 B9 9, S10 B10 10);
+impl_options!(12; S0 B0 0, S1 B1 1, S2 B2 2, S3 B3 3, S4 B4 4, S5 B5 5, S6 B6 6, S7 B7 7,
+    S8 B8 8, S9 B9 9, S10 B10 10, S11 B11 11);
+
+pub struct HighestScore<T>(T);
+
+impl<T> BehaviourExt for HighestScore<T> {}
+
+pub fn highest_score<T>(options: T) -> HighestScore<T> {
+    HighestScore(options)
+}
+
+impl<T> HighestScore<T> {
+    /// The index of the option with the highest score.
+    pub fn choose<STATE, CONTEXT>(&self, state: &STATE, context: &CONTEXT) -> usize
+    where
+        T: UtilityOptions<STATE, CONTEXT>,
+    {
+        let mut best = 0;
+        let mut best_score = self.0.score(0, state, context);
+        for index in 1..T::COUNT {
+            let score = self.0.score(index, state, context);
+            if score > best_score {
+                best = index;
+                best_score = score;
+            }
+        }
+        best
+    }
+}
+
+impl<T, STATE, CONTEXT> Behaviour<STATE, CONTEXT> for HighestScore<T>
+where
+    T: UtilityOptions<STATE, CONTEXT>,
+{
+    fn act(&self, state: &STATE, context: &CONTEXT) -> STATE {
+        let index = self.choose(state, context);
+        self.0.act_option(index, state, context)
+    }
+
+    fn act_changed(&self, state: &STATE, context: &CONTEXT) -> Option<STATE> {
+        let index = self.choose(state, context);
+        self.0.act_changed_option(index, state, context)
+    }
+}
+
+pub struct WeightedRandom<T>(T);
+
+impl<T> BehaviourExt for WeightedRandom<T> {}
+
+pub fn weighted_random<T>(options: T) -> WeightedRandom<T> {
+    WeightedRandom(options)
+}
+
+impl<T> WeightedRandom<T> {
+    /// The index of a randomly chosen option, or `None` if no option has
+    /// a positive score.
+    pub fn choose<STATE, CONTEXT>(&self, state: &STATE, context: &CONTEXT) -> Option<usize>
+    where
+        T: UtilityOptions<STATE, CONTEXT>,
+        CONTEXT: RngContext,
+    {
+        let mut scores = [0.0f32; MAX_OPTIONS];
+        for (index, score) in scores.iter_mut().enumerate().take(T::COUNT) {
+            *score = self.0.score(index, state, context).max(0.0);
+        }
+        let total: f32 = scores.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut pick = context.with_rng(|rng| rng.gen::<f32>()) * total;
+        for (index, score) in scores.iter().enumerate().take(T::COUNT) {
+            if *score > 0.0 && pick < *score {
+                return Some(index);
+            }
+            pick -= score;
+        }
+        // Rounding can leave us just past the end, so use the last option
+        // that could have been picked.
+        scores[..T::COUNT].iter().rposition(|score| *score > 0.0)
+    }
+}
+
+impl<T, STATE, CONTEXT> Behaviour<STATE, CONTEXT> for WeightedRandom<T>
+where
+    STATE: Clone,
+    T: UtilityOptions<STATE, CONTEXT>,
+    CONTEXT: RngContext,
+{
+    fn act(&self, state: &STATE, context: &CONTEXT) -> STATE {
+        match self.choose(state, context) {
+            Some(index) => self.0.act_option(index, state, context),
+            None => state.clone(),
+        }
+    }
+
+    fn act_changed(&self, state: &STATE, context: &CONTEXT) -> Option<STATE> {
+        let index = self.choose(state, context)?;
+        self.0.act_changed_option(index, state, context)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::from_fn::from_fn;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::cell::RefCell;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Needs {
+        hunger: f32,
+        tiredness: f32,
+    }
+
+    struct World {
+        rng: RefCell<StdRng>,
+    }
+
+    impl RngContext for World {
+        fn with_rng<T, F>(&self, f: F) -> T
+        where
+            F: FnOnce(&mut dyn RngCore) -> T,
+        {
+            f(&mut *self.rng.borrow_mut())
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn options() -> (
+        Scored<impl Fn(&Needs, &World) -> f32, impl Behaviour<Needs, World>>,
+        Scored<impl Fn(&Needs, &World) -> f32, impl Behaviour<Needs, World>>,
+    ) {
+        (
+            scored(
+                |s: &Needs, _: &World| s.hunger,
+                from_fn(|s: &Needs, _: &World| Needs {
+                    hunger: 0.0,
+                    ..s.clone()
+                }),
+            ),
+            scored(
+                |s: &Needs, _: &World| s.tiredness,
+                from_fn(|s: &Needs, _: &World| Needs {
+                    tiredness: 0.0,
+                    ..s.clone()
+                }),
+            ),
+        )
+    }
+
+    fn world(seed: u64) -> World {
+        World {
+            rng: RefCell::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    #[test]
+    fn test_highest_score() {
+        let b = highest_score(options());
+        let _m: &dyn Behaviour<Needs, World> = &b;
+
+        let state = Needs {
+            hunger: 0.2,
+            tiredness: 0.7,
+        };
+        assert_eq!(
+            b.act(&state, &world(0)),
+            Needs {
+                hunger: 0.2,
+                tiredness: 0.0
+            }
+        );
+    }
+
+    #[test]
+    fn test_weighted_random() {
+        let b = weighted_random(options());
+        let _m: &dyn Behaviour<Needs, World> = &b;
+
+        // Never picks an option with a zero score.
+        let state = Needs {
+            hunger: 0.0,
+            tiredness: 0.5,
+        };
+        let context = world(1);
+        for _ in 0..20 {
+            assert_eq!(b.choose(&state, &context), Some(1));
+        }
+
+        // Nothing to do.
+        let state = Needs {
+            hunger: 0.0,
+            tiredness: 0.0,
+        };
+        assert_eq!(b.act_changed(&state, &context), None);
+
+        // Picks both over time, and the same seed gives the same choices.
+        let state = Needs {
+            hunger: 0.5,
+            tiredness: 0.5,
+        };
+        let run = |seed| {
+            let context = world(seed);
+            (0..50)
+                .map(|_| b.choose(&state, &context).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let choices = run(7);
+        assert!(choices.contains(&0) && choices.contains(&1));
+        assert_eq!(choices, run(7));
+    }
+}
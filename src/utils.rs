@@ -1,35 +1,144 @@
-#[derive(Debug, Copy, Clone, Ord, PartialOrd, PartialEq, Eq)]
+#[cfg(feature = "derive")]
+pub use rust_agents_derive::{AgentBase, AgentEnum, BaseOp, ColorOp, SystemOp};
+
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, PartialEq, Eq, Hash)]
 pub struct AgentId(pub u64);
 
 pub trait BaseOp {
     fn id(&self) -> AgentId;
 }
 
+/// Agents whose id is set by the context when they are inserted.
+pub trait SetIdOp {
+    fn set_id(&mut self, id: AgentId);
+}
+
 pub trait AgentBase<REQUEST> {
     fn empty_system_outbox(&mut self) -> Vec<REQUEST>;
 }
 
+/// A context that applies the agents' system requests.
+///
+/// `apply_system_requests` receives all of a step's requests at once, in
+/// the order they are to be applied. By default it applies them one by one
+/// with `apply_system_request`; contexts can override it to apply
+/// removals and insertions in bulk, as long as the result is the same as
+/// applying the requests in the order received.
 pub trait System<REQUEST> {
     type AgentType;
     fn apply_system_request(&mut self, action: REQUEST);
-    fn agents_mut(&mut self) -> Vec<&mut Self::AgentType>;
+    fn apply_system_requests(&mut self, actions: Vec<REQUEST>) {
+        for action in actions {
+            self.apply_system_request(action);
+        }
+    }
+}
+
+/// A context whose agents can be visited in place, e.g. to empty their
+/// outboxes, or looked up by id. Every `MapContext` is one.
+pub trait AgentsMut<AGENT> {
+    fn for_each_agent_mut<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut AGENT);
+    fn agent_mut(&mut self, id: AgentId) -> Option<&mut AGENT>;
+}
+
+impl<T, AGENT> AgentsMut<AGENT> for T
+where
+    T: MapContext<AGENT>,
+{
+    fn for_each_agent_mut<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut AGENT),
+    {
+        self.agents_mut().values_mut().for_each(f)
+    }
+
+    fn agent_mut(&mut self, id: AgentId) -> Option<&mut AGENT> {
+        self.agents_mut().get_mut(&id)
+    }
+}
+
+/// The order in which a step's system requests are applied.
+///
+/// Requests are applied in order of their keys. Requests with equal keys
+/// keep the order they were made in, i.e. in order of the requesting
+/// agents' ids. A key can put requests into phases, e.g. all removals
+/// before all creations, and then sort within each phase by something
+/// stable such as the target agent's id, so the result doesn't depend on
+/// which agent happened to make a request.
+///
+/// Any `Fn(&REQUEST) -> K` with an `Ord` key is a `RequestOrder`.
+pub trait RequestOrder<REQUEST> {
+    type Key: Ord;
+    fn key(&self, request: &REQUEST) -> Self::Key;
+    /// Whether the requests need sorting at all. Orders whose keys can't
+    /// tell requests apart, such as `Unordered`, return `false` so the
+    /// requests are left as made.
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
+impl<REQUEST, F, K> RequestOrder<REQUEST> for F
+where
+    F: Fn(&REQUEST) -> K,
+    K: Ord,
+{
+    type Key = K;
+    fn key(&self, request: &REQUEST) -> K {
+        self(request)
+    }
+}
+
+/// Applies requests in the order they were made.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Unordered;
+
+impl<REQUEST> RequestOrder<REQUEST> for Unordered {
+    type Key = ();
+    fn key(&self, _request: &REQUEST) {}
+    fn is_ordered(&self) -> bool {
+        false
+    }
 }
 
+/// Gathers the agents' system requests and applies them in the order they
+/// were made.
 pub fn perform_system_actions<T, REQUEST>(context: &mut T)
 where
-    T: System<REQUEST>,
+    T: System<REQUEST> + AgentsMut<<T as System<REQUEST>>::AgentType>,
     T::AgentType: AgentBase<REQUEST>,
 {
-    // We do this is two loops as we can't modify the
+    perform_ordered_system_actions(context, &Unordered)
+}
+
+/// Gathers the agents' system requests and applies them in `order`.
+pub fn perform_ordered_system_actions<T, REQUEST, O>(context: &mut T, order: &O)
+where
+    T: System<REQUEST> + AgentsMut<<T as System<REQUEST>>::AgentType>,
+    T::AgentType: AgentBase<REQUEST>,
+    O: RequestOrder<REQUEST>,
+{
+    // We do this is two passes as we can't modify the
     // context while iterating over part of it.
     let mut system_actions = vec![];
-    for agent in &mut context.agents_mut() {
-        system_actions.append(&mut agent.empty_system_outbox());
+    context.for_each_agent_mut(|agent| system_actions.append(&mut agent.empty_system_outbox()));
+    if order.is_ordered() {
+        system_actions.sort_by_cached_key(|action| order.key(action));
     }
 
-    for action in system_actions {
-        context.apply_system_request(action);
-    }
+    context.apply_system_requests(system_actions);
+}
+
+/// A context that moves messages between its agents.
+///
+/// `gather_messages` empties the agents' outboxes, and `deliver_messages`
+/// replaces their inboxes with the given messages. A `Simulation` only
+/// needs it once set up `with_messages`.
+pub trait MessageSystem<MESSAGE> {
+    fn gather_messages(&mut self) -> Vec<MESSAGE>;
+    fn deliver_messages(&mut self, messages: Vec<MESSAGE>);
 }
 
 #[derive(Debug, Clone)]
@@ -51,7 +160,13 @@ pub trait SystemOp {
 
 use std::collections::BTreeMap;
 
-use crate::{behaviour::Behaviour, map_context::MapContext};
+use crate::{
+    behaviour::Behaviour,
+    behaviour_mut::BehaviourMut,
+    map_context::MapContext,
+    remove_self::RemoveAgent,
+    try_behaviour::{AgentError, ErrorPolicy, StepResult, TryBehaviour},
+};
 
 pub fn map_tree_leaves<A, B, C, F>(tree: &BTreeMap<A, B>, f: F) -> BTreeMap<A, C>
 where
@@ -61,13 +176,199 @@ where
     tree.iter().map(|(k, v)| (k.clone(), f(v))).collect()
 }
 
+/// Applies the behaviour to every agent in the context.
+///
+/// All new states are calculated against the unchanged context before any
+/// of them are written back, so every agent sees the same time-step.
+/// Agents the behaviour leaves untouched (see `Behaviour::act_changed`)
+/// are not cloned or replaced. The changed agents are written back one at
+/// a time through `MapContext::agents_mut`, so `set_agents` isn't called;
+/// the agents end up the same as if the whole map had been replaced.
 pub fn step_agents<B, AGENT, CONTEXT>(behaviour: &B, context: &mut CONTEXT)
 where
     B: Behaviour<AGENT, CONTEXT>,
-    AGENT: ,
     CONTEXT: MapContext<AGENT>,
 {
-    context.set_agents(map_tree_leaves(&context.agents(), |agent| {
-        behaviour.act(agent, context)
-    }));
+    let changed: Vec<(AgentId, AGENT)> = context
+        .agents()
+        .iter()
+        .filter_map(|(k, agent)| behaviour.act_changed(agent, context).map(|a| (*k, a)))
+        .collect();
+    let agents = context.agents_mut();
+    for (k, agent) in changed {
+        agents.insert(k, agent);
+    }
+}
+
+/// Applies an in-place behaviour to every agent in the context.
+///
+/// Each agent is cloned once and the behaviour updates the clone, so as
+/// with `step_agents` every agent sees the context as it was at the start
+/// of the step. The updated agents are written back at the end.
+pub fn step_agents_mut<B, AGENT, CONTEXT>(behaviour: &B, context: &mut CONTEXT)
+where
+    B: BehaviourMut<AGENT, CONTEXT>,
+    AGENT: Clone,
+    CONTEXT: MapContext<AGENT>,
+{
+    let updated: Vec<(AgentId, AGENT)> = context
+        .agents()
+        .iter()
+        .map(|(k, agent)| {
+            let mut agent = agent.clone();
+            behaviour.act_mut(&mut agent, context);
+            (*k, agent)
+        })
+        .collect();
+    let agents = context.agents_mut();
+    for (k, agent) in updated {
+        agents.insert(k, agent);
+    }
+}
+
+/// Applies a fallible behaviour to every agent in the context.
+///
+/// As with `step_agents` all new states are calculated before any are
+/// written back. The agents whose behaviour failed are then handled
+/// according to `policy`, and their errors returned. Under
+/// `ErrorPolicy::AbortStep` no agent is changed and the errors are
+/// returned as `Err`.
+///
+/// The failed agents are never removed here, since only the context's
+/// `System` knows what else removing an agent involves. To remove them,
+/// pass the errors to `remove_failed_agents`.
+pub fn try_step_agents<B, AGENT, CONTEXT>(
+    behaviour: &B,
+    context: &mut CONTEXT,
+    policy: ErrorPolicy,
+) -> StepResult<B::Error>
+where
+    B: TryBehaviour<AGENT, CONTEXT>,
+    CONTEXT: MapContext<AGENT>,
+{
+    let mut updated: Vec<(AgentId, AGENT)> = vec![];
+    let mut errors: Vec<AgentError<B::Error>> = vec![];
+    for (k, agent) in context.agents() {
+        match behaviour.try_act(agent, context) {
+            Ok(agent) => updated.push((*k, agent)),
+            Err(error) => errors.push(AgentError { id: *k, error }),
+        }
+    }
+
+    if policy == ErrorPolicy::AbortStep && !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let agents = context.agents_mut();
+    for (k, agent) in updated {
+        agents.insert(k, agent);
+    }
+    Ok(errors)
+}
+
+/// Removes the agents whose behaviour failed by applying a `RemoveAgent`
+/// request for each, so they go through the context's normal removal path.
+pub fn remove_failed_agents<T, REQUEST, E>(context: &mut T, errors: &[AgentError<E>])
+where
+    T: System<REQUEST>,
+    REQUEST: From<RemoveAgent>,
+{
+    context.apply_system_requests(
+        errors
+            .iter()
+            .map(|error| RemoveAgent(error.id).into())
+            .collect(),
+    );
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    struct Agent {
+        outbox: Vec<(char, u32)>,
+    }
+
+    impl AgentBase<(char, u32)> for Agent {
+        fn empty_system_outbox(&mut self) -> Vec<(char, u32)> {
+            self.outbox.drain(..).collect()
+        }
+    }
+
+    struct Context {
+        agents: Vec<Agent>,
+        applied: Vec<(char, u32)>,
+    }
+
+    impl System<(char, u32)> for Context {
+        type AgentType = Agent;
+        fn apply_system_request(&mut self, action: (char, u32)) {
+            self.applied.push(action);
+        }
+    }
+
+    impl AgentsMut<Agent> for Context {
+        fn for_each_agent_mut<F>(&mut self, f: F)
+        where
+            F: FnMut(&mut Agent),
+        {
+            self.agents.iter_mut().for_each(f)
+        }
+
+        // The agents are identified by their position.
+        fn agent_mut(&mut self, id: AgentId) -> Option<&mut Agent> {
+            self.agents.get_mut(id.0 as usize)
+        }
+    }
+
+    fn context() -> Context {
+        Context {
+            agents: vec![
+                Agent {
+                    outbox: vec![('c', 2), ('r', 5)],
+                },
+                Agent {
+                    outbox: vec![('c', 1), ('m', 3), ('r', 4)],
+                },
+            ],
+            applied: vec![],
+        }
+    }
+
+    #[test]
+    fn test_request_order() {
+        let mut unordered = context();
+        let _s: &dyn System<(char, u32), AgentType = Agent> = &unordered;
+        assert!(!RequestOrder::<(char, u32)>::is_ordered(&Unordered));
+        perform_system_actions(&mut unordered);
+        assert_eq!(
+            unordered.applied,
+            vec![('c', 2), ('r', 5), ('c', 1), ('m', 3), ('r', 4)]
+        );
+
+        // Removes, then modifies, then creates, each sorted by target.
+        let phases = |action: &(char, u32)| {
+            let phase = match action.0 {
+                'r' => 0,
+                'm' => 1,
+                _ => 2,
+            };
+            (phase, action.1)
+        };
+        let mut ordered = context();
+        perform_ordered_system_actions(&mut ordered, &phases);
+        assert_eq!(
+            ordered.applied,
+            vec![('r', 4), ('r', 5), ('m', 3), ('c', 1), ('c', 2)]
+        );
+
+        // Requests with equal keys keep the order they were made in.
+        let mut phases_only = context();
+        perform_ordered_system_actions(&mut phases_only, &|action: &(char, u32)| action.0 == 'c');
+        assert_eq!(
+            phases_only.applied,
+            vec![('r', 5), ('m', 3), ('r', 4), ('c', 2), ('c', 1)]
+        );
+    }
 }
@@ -0,0 +1,57 @@
+/// Creates a behaviour that is only applied when a predicate on the
+/// state and context holds.
+///
+/// This is usually accessed via `BehaviourExt::when` not the `When`
+/// struct directly. States for which the predicate fails are passed
+/// through unchanged (and uncloned when called through `act_changed`).
+use crate::behaviour::Behaviour;
+use crate::behaviour_ext::BehaviourExt;
+use crate::behaviour_mut::BehaviourMut;
+
+pub struct When<A, P> {
+    a: A,
+    predicate: P,
+}
+
+impl<A, P> BehaviourExt for When<A, P> {}
+
+impl<A, P> When<A, P> {
+    pub fn new(a: A, predicate: P) -> Self {
+        When { a, predicate }
+    }
+}
+
+impl<A, P, STATE, CONTEXT> Behaviour<STATE, CONTEXT> for When<A, P>
+where
+    STATE: Clone,
+    P: Fn(&STATE, &CONTEXT) -> bool,
+    A: Behaviour<STATE, CONTEXT>,
+{
+    fn act(&self, state: &STATE, context: &CONTEXT) -> STATE {
+        if (self.predicate)(state, context) {
+            self.a.act(state, context)
+        } else {
+            state.clone()
+        }
+    }
+
+    fn act_changed(&self, state: &STATE, context: &CONTEXT) -> Option<STATE> {
+        if (self.predicate)(state, context) {
+            self.a.act_changed(state, context)
+        } else {
+            None
+        }
+    }
+}
+
+impl<A, P, STATE, CONTEXT> BehaviourMut<STATE, CONTEXT> for When<A, P>
+where
+    P: Fn(&STATE, &CONTEXT) -> bool,
+    A: BehaviourMut<STATE, CONTEXT>,
+{
+    fn act_mut(&self, state: &mut STATE, context: &CONTEXT) {
+        if (self.predicate)(state, context) {
+            self.a.act_mut(state, context);
+        }
+    }
+}
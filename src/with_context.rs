@@ -0,0 +1,56 @@
+/// Runs a behaviour written for one context inside a different context,
+/// using a projection from the outer context to the inner one.
+///
+/// This is usually accessed via `with_mapped_context` or
+/// `BehaviourExt::with_context` not the `WithContext` struct directly.
+///
+/// This lets a behaviour with context requirements (e.g. a context that
+/// implements some `NeighborhoodContext` trait) be reused inside a bigger
+/// context that just contains such a context as one of its fields.
+use crate::behaviour::Behaviour;
+use crate::behaviour_ext::BehaviourExt;
+use crate::behaviour_mut::BehaviourMut;
+
+pub struct WithContext<A, F> {
+    a: A,
+    f: F,
+}
+
+impl<A, F> BehaviourExt for WithContext<A, F> {}
+
+impl<A, F> WithContext<A, F> {
+    pub fn new(a: A, f: F) -> Self {
+        WithContext { a, f }
+    }
+}
+
+pub fn with_mapped_context<A, F, CONTEXT, INNER>(f: F, a: A) -> WithContext<A, F>
+where
+    F: Fn(&CONTEXT) -> &INNER,
+{
+    WithContext::new(a, f)
+}
+
+impl<A, F, STATE, CONTEXT, INNER> Behaviour<STATE, CONTEXT> for WithContext<A, F>
+where
+    F: Fn(&CONTEXT) -> &INNER,
+    A: Behaviour<STATE, INNER>,
+{
+    fn act(&self, state: &STATE, context: &CONTEXT) -> STATE {
+        self.a.act(state, (self.f)(context))
+    }
+
+    fn act_changed(&self, state: &STATE, context: &CONTEXT) -> Option<STATE> {
+        self.a.act_changed(state, (self.f)(context))
+    }
+}
+
+impl<A, F, STATE, CONTEXT, INNER> BehaviourMut<STATE, CONTEXT> for WithContext<A, F>
+where
+    F: Fn(&CONTEXT) -> &INNER,
+    A: BehaviourMut<STATE, INNER>,
+{
+    fn act_mut(&self, state: &mut STATE, context: &CONTEXT) {
+        self.a.act_mut(state, (self.f)(context))
+    }
+}
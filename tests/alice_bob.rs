@@ -1,10 +1,22 @@
 extern crate rust_agents;
 
 use rust_agents::behaviour::Behaviour;
+use rust_agents::conflicts::{
+    first_wins, perform_checked_system_actions, validate, Pending, Rejected, RejectionsOp,
+    RequestPolicy,
+};
+use rust_agents::from_fn::from_fn;
+use rust_agents::map_context::MapContext;
+use rust_agents::messaging::{
+    gather_messages, DeliveryFailure, Envelope, InboxOp, OutboxOp, PostOffice,
+};
+use rust_agents::names::{NameRegistry, NameResolver, NamedContext};
+use rust_agents::simulation::{Simulation, StepLimit};
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 
 use rust_agents::utils::{
-    map_tree_leaves, perform_system_actions, AgentBase, AgentId, BaseOp, Color, ColorOp, System,
+    AgentBase, AgentId, BaseOp, Color, ColorOp, MessageSystem, System, Unordered,
 };
 
 /// Stateless behaviour for Alice.
@@ -27,25 +39,19 @@ struct AliceBehaviour {}
 /// However the state (which includes the inbox) has the following requirements:
 /// * we must be able to clone the state to get the next state so that the new
 ///   state is completely independent of the old state - this means we must support Clone
-/// * get incoming messages and sending messages - must support MessageOp trait.
+/// * get incoming messages and sending messages - must support InboxOp and OutboxOp.
 /// * set the agents colour - ColorOp
 /// * remove its self from simulation - SystemOp
 impl<STATE, CONTEXT> Behaviour<STATE, CONTEXT> for AliceBehaviour
 where
-    STATE: BaseOp + ColorOp + MessageOp + SystemOp + Clone,
+    STATE: BaseOp + ColorOp + InboxOp<MessageBody> + OutboxOp<MessageBody> + SystemOp + Clone,
 {
     fn act(&self, state: &STATE, _context: &CONTEXT) -> STATE {
         let mut state = state.clone();
 
-        let inbox = state.inbox();
-        let greetings: Vec<&Message> = inbox
-            .iter()
-            .filter(|m| match m.body {
-                MessageBody::Greeting(_) => true,
-            })
-            .collect();
+        let greeted_by: Vec<AgentId> = state.messages::<&Greeting>().map(|m| m.from).collect();
 
-        if greetings.len() > 0 {
+        if !greeted_by.is_empty() {
             state.set_color(Color::Blue);
             state.request(SystemRequest {
                 from: state.id(),
@@ -53,16 +59,14 @@ where
             });
         }
 
-        greetings.iter().for_each(|m| {
-            let reply = Message {
-                to: m.from,
-                from: state.id(),
-                body: MessageBody::Greeting(Greeting {
+        for from in greeted_by {
+            state.send_to(
+                from,
+                MessageBody::Greeting(Greeting {
                     msg: "Go away, I’m social-distancing!".to_string(),
                 }),
-            };
-            state.send(reply);
-        });
+            );
+        }
 
         state
     }
@@ -82,14 +86,14 @@ struct BobBehaviour {}
 ///
 /// In Bob's case we do need a way to find Alice - in this case that
 /// means we want the CONTEXT to support a way to get an AgentId from
-/// a name - so we require CONTEXT to implement a new NameResolver trait.
+/// a name - so we require CONTEXT to implement the NameResolver trait.
 ///
 /// Bob also needs to change colour, receive messages and remove himsefl
 /// from the simulation, meaning we require the STATE to implement
-/// ColorOpt, MessageOp, SystemOp as well as the "usual" BaseOp and Clone.
+/// ColorOpt, InboxOp, OutboxOp, SystemOp as well as the "usual" BaseOp and Clone.
 impl<STATE, CONTEXT> Behaviour<STATE, CONTEXT> for BobBehaviour
 where
-    STATE: BaseOp + ColorOp + MessageOp + SystemOp + Clone,
+    STATE: BaseOp + ColorOp + InboxOp<MessageBody> + OutboxOp<MessageBody> + SystemOp + Clone,
     CONTEXT: NameResolver,
 {
     fn act(&self, state: &STATE, context: &CONTEXT) -> STATE {
@@ -97,14 +101,12 @@ where
         let alice_id = context.resolve_id_from_name("Alice");
         match alice_id {
             Some(alice_id) => {
-                let message = Message {
-                    from: state.id(),
-                    to: alice_id,
-                    body: MessageBody::Greeting(Greeting {
+                state.send_to(
+                    alice_id,
+                    MessageBody::Greeting(Greeting {
                         msg: "Hello, Alice.".to_string(),
                     }),
-                };
-                state.send(message);
+                );
             }
             None => {
                 state.request(SystemRequest {
@@ -114,15 +116,9 @@ where
             }
         };
 
-        let inbox = state.inbox();
-        let greetings: Vec<&Message> = inbox
-            .iter()
-            .filter(|m| match m.body {
-                MessageBody::Greeting(_) => true,
-            })
-            .collect();
+        let greeted = state.messages::<&Greeting>().next().is_some();
 
-        if greetings.len() > 0 {
+        if greeted {
             state.set_color(Color::Red);
         }
 
@@ -130,25 +126,13 @@ where
     }
 }
 
-/// A trait for states that send and receive messages
-/// In this case hardcoded to receive alice_bob::Message
-trait MessageOp {
-    // TODO: Better to use an iterator than allocate?
-    // The Message type should be only those that Alice can understand
-    fn inbox(&self) -> Vec<Message>;
-    fn send(&mut self, message: Message);
-}
-
 /// A trait for states that can respond to SystemRequest messages
 trait SystemOp {
     fn request(&mut self, request: SystemRequest);
 }
 
-trait NameResolver {
-    fn resolve_id_from_name(&self, name: &str) -> Option<AgentId>;
-}
-
 /// The state for an Alice or Bob Agent
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 struct AgentState {
     id: AgentId,
@@ -158,6 +142,7 @@ struct AgentState {
     inbox: Vec<Message>,
     outbox: Vec<Message>,
     system_outbox: Vec<SystemRequest>,
+    rejections: Vec<Rejected<SystemRequest>>,
 }
 
 impl BaseOp for AgentState {
@@ -175,12 +160,18 @@ impl ColorOp for AgentState {
     }
 }
 
-impl MessageOp for AgentState {
-    fn inbox(&self) -> Vec<Message> {
-        self.inbox.clone()
+impl InboxOp<MessageBody> for AgentState {
+    fn inbox(&self) -> &[Message] {
+        &self.inbox
+    }
+    fn inbox_mut(&mut self) -> &mut Vec<Message> {
+        &mut self.inbox
     }
-    fn send(&mut self, message: Message) {
-        self.outbox.push(message)
+}
+
+impl OutboxOp<MessageBody> for AgentState {
+    fn outbox_mut(&mut self) -> &mut Vec<Message> {
+        &mut self.outbox
     }
 }
 
@@ -195,6 +186,7 @@ struct Greeting {
     msg: String,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct RemoveAgentMessage {
     to_remove: AgentId,
@@ -205,13 +197,18 @@ enum MessageBody {
     Greeting(Greeting),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct Message {
-    to: AgentId,
-    from: AgentId,
-    body: MessageBody,
+/// Lets behaviours view just the greetings in their inbox.
+impl<'a> TryFrom<&'a MessageBody> for &'a Greeting {
+    type Error = ();
+    fn try_from(body: &'a MessageBody) -> Result<Self, ()> {
+        match body {
+            MessageBody::Greeting(greeting) => Ok(greeting),
+        }
+    }
 }
 
+type Message = Envelope<MessageBody>;
+
 #[derive(Debug, Clone)]
 enum SystemRequestBody {
     RemoveAgent(AgentId),
@@ -236,7 +233,7 @@ enum AgentBehaviour {
 
 impl<STATE, CONTEXT> Behaviour<STATE, CONTEXT> for AgentBehaviour
 where
-    STATE: BaseOp + ColorOp + MessageOp + SystemOp + Clone,
+    STATE: BaseOp + ColorOp + InboxOp<MessageBody> + OutboxOp<MessageBody> + SystemOp + Clone,
     CONTEXT: NameResolver,
 {
     fn act(&self, state: &STATE, context: &CONTEXT) -> STATE {
@@ -251,7 +248,7 @@ where
 ///
 /// In general the behaviour is stateless, does not change, and may be shared between more than one
 /// agent, while the state is changed every timestep.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Agent {
     behaviour: AgentBehaviour,
     state: AgentState,
@@ -268,6 +265,22 @@ impl Agent {
     }
 }
 
+/// Messages are delivered to and gathered from the agent's state.
+impl InboxOp<MessageBody> for Agent {
+    fn inbox(&self) -> &[Message] {
+        self.state.inbox()
+    }
+    fn inbox_mut(&mut self) -> &mut Vec<Message> {
+        self.state.inbox_mut()
+    }
+}
+
+impl OutboxOp<MessageBody> for Agent {
+    fn outbox_mut(&mut self) -> &mut Vec<Message> {
+        self.state.outbox_mut()
+    }
+}
+
 /// At the end of each timestep we get any system requests
 /// for each acgent. To allow us to do this Agent must support
 /// the AgentBase trait. (But since the available SystemRequests
@@ -281,6 +294,22 @@ impl AgentBase<SystemRequest> for Agent {
     }
 }
 
+impl BaseOp for Agent {
+    fn id(&self) -> AgentId {
+        self.state.id()
+    }
+}
+
+/// Requests the context refused are handed back to the agent's state.
+impl RejectionsOp<SystemRequest> for Agent {
+    fn rejections(&self) -> &[Rejected<SystemRequest>] {
+        &self.state.rejections
+    }
+    fn rejections_mut(&mut self) -> &mut Vec<Rejected<SystemRequest>> {
+        &mut self.state.rejections
+    }
+}
+
 /// For Alice and Bob we have simple maps form ids to agents,
 /// and names to ids.
 ///
@@ -293,43 +322,48 @@ impl AgentBase<SystemRequest> for Agent {
 /// }
 ///
 /// would suffice.
-
+#[derive(Clone)]
 struct GlobalContext {
     agents: BTreeMap<AgentId, Agent>,
-    name_to_agent_id: BTreeMap<String, AgentId>,
+    names: NameRegistry,
+    post_office: PostOffice<MessageBody>,
 }
 
 impl GlobalContext {
     fn new() -> Self {
         GlobalContext {
             agents: BTreeMap::new(),
-            name_to_agent_id: BTreeMap::new(),
+            names: NameRegistry::new(),
+            post_office: PostOffice::new(),
         }
     }
 }
 
-impl NameResolver for GlobalContext {
-    fn resolve_id_from_name(&self, name: &str) -> Option<AgentId> {
-        self.name_to_agent_id.get(name).cloned()
+/// Gives GlobalContext a NameResolver, and removes an agent's name when
+/// the agent is removed.
+impl NamedContext for GlobalContext {
+    fn names(&self) -> &NameRegistry {
+        &self.names
+    }
+    fn names_mut(&mut self) -> &mut NameRegistry {
+        &mut self.names
     }
 }
 
 fn print_agents(context: &GlobalContext) {
-    for (_agent_id, agent) in &context.agents {
+    for agent in context.agents.values() {
         println!("  {:?}", agent)
     }
 }
 
-fn step_agents(context: &mut GlobalContext) {
-    context.agents = map_tree_leaves(&context.agents, |agent| agent.act(context));
-}
+impl MapContext<Agent> for GlobalContext {
+    fn agents(&self) -> &BTreeMap<AgentId, Agent> {
+        &self.agents
+    }
 
-fn gather_messages(context: &mut GlobalContext) -> Vec<Message> {
-    let mut messages = vec![];
-    for (_agent_id, agent) in &mut context.agents {
-        messages.append(&mut agent.state.outbox);
+    fn agents_mut(&mut self) -> &mut BTreeMap<AgentId, Agent> {
+        &mut self.agents
     }
-    messages
 }
 
 impl System<SystemRequest> for GlobalContext {
@@ -339,40 +373,25 @@ impl System<SystemRequest> for GlobalContext {
         match request.body {
             SystemRequestBody::RemoveAgent(agent_id) => {
                 println!("{:?} requested removal of {:?}", request.from, agent_id);
-                let agent = self.agents.remove(&agent_id).unwrap();
-                self.name_to_agent_id.remove(&agent.state.name);
+                let agent: Option<Agent> = self.remove_agent(agent_id);
+                assert!(agent.is_some());
             }
         }
     }
-    fn agents_mut(&mut self) -> Vec<&mut Agent> {
-        return self
-            .agents
-            .iter_mut()
-            .map(|(_agent_id, agent)| agent)
-            .collect();
-    }
 }
 
-fn deliver_messages(context: &mut GlobalContext, messages: Vec<Message>) {
-    for (_agent_id, agent) in &mut context.agents {
-        agent.state.inbox.clear();
+impl MessageSystem<Message> for GlobalContext {
+    fn gather_messages(&mut self) -> Vec<Message> {
+        gather_messages(&mut self.agents)
     }
 
-    for message in messages {
-        println!("MESSAGE: {:?}", message);
-        match context.agents.get_mut(&message.to) {
-            Some(agent) => {
-                agent.state.inbox.push(message);
-            }
-            None => {
-                println!("No agent {:?}", message.to);
-            }
-        }
+    fn deliver_messages(&mut self, messages: Vec<Message>) {
+        self.post_office
+            .deliver(&mut self.agents, &self.names, messages);
     }
 }
 
-#[test]
-fn test_main() {
+fn initial_context() -> GlobalContext {
     let curstate: Vec<Agent> = vec![
         Agent {
             behaviour: AgentBehaviour::Alice(AliceBehaviour {}),
@@ -384,6 +403,7 @@ fn test_main() {
                 inbox: vec![],
                 outbox: vec![],
                 system_outbox: vec![],
+                rejections: vec![],
             },
         },
         Agent {
@@ -396,29 +416,150 @@ fn test_main() {
                 inbox: vec![],
                 outbox: vec![],
                 system_outbox: vec![],
+                rejections: vec![],
             },
         },
     ];
 
-    let mut agents: BTreeMap<AgentId, Agent> = BTreeMap::new();
-    let mut name_to_agent_id: BTreeMap<String, AgentId> = BTreeMap::new();
-
+    let mut context = GlobalContext::new();
     for a in curstate {
-        name_to_agent_id.insert(a.state.name.clone(), a.state.id);
-        agents.insert(a.state.id, a);
+        context.names.register(a.state.id, &a.state.name).unwrap();
+        context.agents.insert(a.state.id, a);
     }
+    context
+}
 
-    let mut context = GlobalContext::new();
-    context.name_to_agent_id = name_to_agent_id;
-    context.agents = agents;
+#[test]
+fn test_main() {
+    let context = initial_context();
+
+    let behaviour = from_fn(|agent: &Agent, context: &GlobalContext| agent.act(context));
+    let mut simulation =
+        Simulation::<_, _, _, SystemRequest>::new(behaviour, context).with_messages::<Message>();
+
+    // Bob greets Alice, Alice replies and leaves, and Bob leaves once he
+    // finds she has gone.
+    let steps = simulation.run_until(|context| {
+        print_agents(context);
+        context.agents.is_empty()
+    });
+    assert_eq!(steps, 3);
+
+    // Bob greeted Alice again in the step she left, so that greeting
+    // couldn't be delivered.
+    let dead_letters = simulation.context().post_office.dead_letters();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(
+        dead_letters[0].reason,
+        DeliveryFailure::NoSuchAgent(AgentId(111))
+    );
+}
 
-    for i in 0..10 {
-        println!("Step {}", i);
-        print_agents(&context);
-        step_agents(&mut context);
+#[test]
+fn test_snapshot() {
+    let behaviour = from_fn(|agent: &Agent, context: &GlobalContext| agent.act(context));
+    let mut simulation = Simulation::<_, _, _, SystemRequest>::new(behaviour, initial_context())
+        .with_messages::<Message>();
+
+    // Bob's first greeting is waiting in Alice's inbox.
+    simulation.step();
+    let snapshot = simulation.snapshot();
+
+    let steps = simulation.run_until(|context| context.agents.is_empty());
+    assert_eq!(steps, 2);
+
+    simulation.restore(snapshot);
+    assert_eq!(simulation.steps(), 1);
+    assert_eq!(
+        simulation.run_until_or(1, |context| context.agents.is_empty()),
+        Err(StepLimit(1))
+    );
+    assert_eq!(
+        simulation.run_until_or(10, |context| context.agents.is_empty()),
+        Ok(1)
+    );
+    assert_eq!(simulation.context().post_office.dead_letters().len(), 1);
+}
 
-        let messages = gather_messages(&mut context);
-        perform_system_actions(&mut context);
-        deliver_messages(&mut context, messages);
+/// Both Alice and Bob ask for Alice to be removed, and Bob also asks for an
+/// agent that doesn't exist to be removed. Applied as they are, the second
+/// removal of Alice would fail.
+fn conflicting_removals() -> GlobalContext {
+    let mut context = initial_context();
+    let alice = AgentId(111);
+    let bob = AgentId(222);
+
+    for id in &[alice, bob] {
+        context.agents.get_mut(id).unwrap().state.system_outbox = vec![SystemRequest {
+            from: *id,
+            body: SystemRequestBody::RemoveAgent(alice),
+        }];
     }
+    context
+        .agents
+        .get_mut(&bob)
+        .unwrap()
+        .state
+        .system_outbox
+        .push(SystemRequest {
+            from: bob,
+            body: SystemRequestBody::RemoveAgent(AgentId(333)),
+        });
+    context
+}
+
+fn removal_policy() -> impl RequestPolicy<GlobalContext, SystemRequest> {
+    (
+        validate(
+            |context: &GlobalContext, pending: &Pending<SystemRequest>| match pending.request.body {
+                SystemRequestBody::RemoveAgent(id) if !context.agents.contains_key(&id) => {
+                    Err(format!("{:?} doesn't exist", id))
+                }
+                _ => Ok(()),
+            },
+        ),
+        first_wins(|request: &SystemRequest| match request.body {
+            SystemRequestBody::RemoveAgent(id) => Some(id),
+        }),
+    )
+}
+
+fn rejection_reasons(context: &GlobalContext, id: AgentId) -> Vec<&str> {
+    context.agents[&id]
+        .rejections()
+        .iter()
+        .map(|r| r.reason.as_str())
+        .collect()
+}
+
+#[test]
+fn test_conflicting_removals() {
+    let mut context = conflicting_removals();
+    let rejected = perform_checked_system_actions(&mut context, &Unordered, &mut removal_policy());
+
+    assert!(!context.agents.contains_key(&AgentId(111)));
+    assert_eq!(
+        rejection_reasons(&context, AgentId(222)),
+        vec![
+            "conflicts with an earlier request from AgentId(111)",
+            "AgentId(333) doesn't exist",
+        ]
+    );
+    assert_eq!(rejected.len(), 2);
+}
+
+#[test]
+fn test_conflict_policy_in_simulation() {
+    // The agents just keep the requests they were given.
+    let behaviour = from_fn(|agent: &Agent, _context: &GlobalContext| agent.clone());
+    let mut simulation =
+        Simulation::<_, _, _, SystemRequest>::new(behaviour, conflicting_removals())
+            .with_conflict_policy(removal_policy());
+
+    simulation.step();
+    assert!(!simulation.context().agents.contains_key(&AgentId(111)));
+    assert_eq!(
+        rejection_reasons(simulation.context(), AgentId(222)).len(),
+        2
+    );
 }
@@ -7,13 +7,19 @@ use cgmath::Vector3;
 use rand::prelude::*;
 
 use rust_agents::chain::Chain;
+use rust_agents::ids::{IdAllocator, IdContext, IdStatus};
 use rust_agents::remove_self::{RemoveAgent, RemoveSelfBehaviour};
-use rust_agents::{behaviour::Behaviour, map_context::MapContext, utils::step_agents};
+use rust_agents::simulation::Simulation;
+use rust_agents::utils::{remove_failed_agents, try_step_agents};
+use rust_agents::{behaviour::Behaviour, map_context::MapContext};
 
 use rust_agents::act_map_if::{act_map_if, TryIntoResult};
-use rust_agents::utils::{
-    map_tree_leaves, perform_system_actions, AgentBase, AgentId, BaseOp, System, SystemOp,
-};
+use rust_agents::act_map_ref::act_map_ref;
+use rust_agents::behaviour_ext::BehaviourExt;
+use rust_agents::parallel::{parallel, weighted_merge};
+use rust_agents::try_behaviour::{try_act_map_if, AgentError, ErrorPolicy, TryBehaviour};
+use rust_agents::utils::{AgentBase, AgentId, BaseOp, System, SystemOp};
+use rust_agents::with_context::with_mapped_context;
 
 #[derive(Clone, Debug)]
 struct CreateAgent {
@@ -24,6 +30,8 @@ struct CreateAgent {
 
 struct FlockCreator {}
 
+impl BehaviourExt for FlockCreator {}
+
 impl FlockCreator {
     fn random_unit_vec(rand: &mut impl Rng) -> Vector3<f32> {
         Vector3::new(
@@ -96,6 +104,8 @@ trait FlockGlobalContext {
 
 struct FlockBehaviour {}
 
+impl BehaviourExt for FlockBehaviour {}
+
 impl<STATE, CONTEXT> Behaviour<STATE, CONTEXT> for FlockBehaviour
 where
     STATE: Clone + PositionAndDirectionOp,
@@ -118,7 +128,7 @@ where
         let direction = state.direction();
 
         // Alignment: steer along the average direction of neighbors
-        let mut align_vec = direction.clone();
+        let mut align_vec = direction;
         let mut count = 1.0;
         context.for_each_neighbour(&state, |_state: &STATE, n: &STATE| {
             align_vec += n.direction();
@@ -128,7 +138,7 @@ where
 
         // Cohesion: steer to move towards the average position (center of mass) of neighbors
         // Separation: steer to avoid crowding local flockmates
-        let mut avg_position = position.clone();
+        let mut avg_position = position;
         context.for_each_neighbour(&state, |_state: &STATE, n: &STATE| {
             avg_position += n.position();
         });
@@ -154,6 +164,32 @@ where
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+enum FlockError {
+    DegenerateDirection(AgentId),
+}
+
+/// FlockBehaviour normalizes the new direction, which gives NaNs if the
+/// boid and its neighbours have no net direction. This version reports
+/// that as an error instead of letting the NaNs spread.
+struct CheckedFlockBehaviour {}
+
+impl<STATE, CONTEXT> TryBehaviour<STATE, CONTEXT> for CheckedFlockBehaviour
+where
+    STATE: Clone + PositionAndDirectionOp + BaseOp,
+    CONTEXT: FlockGlobalContext + NeighborhoodContext<STATE>,
+{
+    type Error = FlockError;
+    fn try_act(&self, state: &STATE, context: &CONTEXT) -> Result<STATE, FlockError> {
+        let new_state = FlockBehaviour {}.act(state, context);
+        let direction = new_state.direction();
+        if direction.x.is_nan() || direction.y.is_nan() || direction.z.is_nan() {
+            return Err(FlockError::DegenerateDirection(state.id()));
+        }
+        Ok(new_state)
+    }
+}
+
 #[derive(Clone, Debug)]
 enum SystemRequest {
     CreateAgent(CreateAgent),
@@ -191,6 +227,7 @@ impl BaseOp for Creator {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 struct Boid {
     id: AgentId,
@@ -199,6 +236,12 @@ struct Boid {
     rgb: (u8, u8, u8),
 }
 
+impl BaseOp for Boid {
+    fn id(&self) -> AgentId {
+        self.id
+    }
+}
+
 impl PositionAndDirectionOp for Boid {
     fn position(&self) -> Vector3<f32> {
         self.position
@@ -232,11 +275,60 @@ impl From<Boid> for Agent {
     }
 }
 
-use std::collections::BTreeMap;
+impl Agent {
+    pub fn try_into_creator(self) -> TryIntoResult<Creator, Agent> {
+        match self {
+            Agent::Creator(v) => TryIntoResult::Ok(v),
+            _ => TryIntoResult::Failed(self),
+        }
+    }
+
+    pub fn try_into_boid(self) -> TryIntoResult<Boid, Agent> {
+        match self {
+            Agent::Boid(v) => TryIntoResult::Ok(v),
+            _ => TryIntoResult::Failed(self),
+        }
+    }
+
+    pub fn as_creator(&self) -> Option<&Creator> {
+        match self {
+            Agent::Creator(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_boid(&self) -> Option<&Boid> {
+        match self {
+            Agent::Boid(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+impl BaseOp for Agent {
+    fn id(&self) -> AgentId {
+        match self {
+            Agent::Creator(state) => state.id,
+            Agent::Boid(state) => state.id,
+        }
+    }
+}
+
+impl AgentBase<SystemRequest> for Agent {
+    fn empty_system_outbox(&mut self) -> Vec<SystemRequest> {
+        match self {
+            Agent::Creator(state) => state.system_outbox.drain(..).collect(),
+            _ => vec![],
+        }
+    }
+}
+
+use std::collections::{BTreeMap, BTreeSet};
 
 struct Context {
     globals: FlockGlobals,
     agents: BTreeMap<AgentId, Agent>,
+    ids: IdAllocator,
     search_radius: f32,
 }
 
@@ -250,9 +342,17 @@ impl Context {
                 preferred_flock_size: 4.0,
             },
             agents: BTreeMap::new(),
+            ids: IdAllocator::monotonic(),
             search_radius: 10.0,
         }
     }
+
+    fn remove_all(&mut self, removed: &mut BTreeSet<AgentId>) {
+        if !removed.is_empty() {
+            self.agents.retain(|id, _| !removed.contains(id));
+            removed.clear();
+        }
+    }
 }
 
 impl NeighborhoodContext<Boid> for Context {
@@ -261,13 +361,10 @@ impl NeighborhoodContext<Boid> for Context {
         F: FnMut(&Boid, &Boid),
     {
         for (_k, v) in self.agents.iter() {
-            match v {
-                Agent::Boid(boid) => {
-                    if self_boid.position.distance(boid.position) < self.search_radius {
-                        f(self_boid, boid);
-                    }
+            if let Agent::Boid(boid) = v {
+                if self_boid.position.distance(boid.position) < self.search_radius {
+                    f(self_boid, boid);
                 }
-                _ => {}
             }
         }
     }
@@ -284,58 +381,55 @@ impl System<SystemRequest> for Context {
     fn apply_system_request(&mut self, action: SystemRequest) {
         match action {
             SystemRequest::CreateAgent(request) => {
-                let next_id = self.agents.iter().map(|(k, _v)| k.0).max().unwrap() + 1;
-                let new_agent = Agent::Boid(Boid {
-                    id: AgentId(next_id),
-                    direction: request.direction,
-                    position: request.position,
-                    rgb: request.rgb,
+                let id = self.create_agent(|id| {
+                    Agent::Boid(Boid {
+                        id,
+                        direction: request.direction,
+                        position: request.position,
+                        rgb: request.rgb,
+                    })
                 });
-                self.agents.insert(AgentId(next_id), new_agent);
+                println!("Created boid {:?}", id);
             }
             SystemRequest::RemoveAgent(request) => {
                 let agent_id = request.0;
-                self.agents.remove(&agent_id);
+                let _agent: Option<Agent> = self.destroy_agent(agent_id);
             }
         }
     }
-    fn agents_mut(&mut self) -> Vec<&mut Agent> {
-        return self
-            .agents
-            .iter_mut()
-            .map(|(_agent_id, agent)| agent)
-            .collect();
-    }
-}
-
-impl Agent {
-    pub fn try_into_creator(self) -> TryIntoResult<Creator, Agent> {
-        match self {
-            Agent::Creator(v) => TryIntoResult::Ok(v),
-            _ => TryIntoResult::Failed(self),
-        }
-    }
-
-    pub fn try_into_boid(self) -> TryIntoResult<Boid, Agent> {
-        match self {
-            Agent::Boid(v) => TryIntoResult::Ok(v),
-            _ => TryIntoResult::Failed(self),
+    /// Removes each run of consecutive removals in one pass over the map,
+    /// so the requests still take effect in the order received.
+    fn apply_system_requests(&mut self, actions: Vec<SystemRequest>) {
+        let mut removed = BTreeSet::new();
+        for action in actions {
+            match action {
+                SystemRequest::RemoveAgent(request) => {
+                    if self.ids.release(request.0) {
+                        removed.insert(request.0);
+                    }
+                }
+                action => {
+                    self.remove_all(&mut removed);
+                    self.apply_system_request(action);
+                }
+            }
         }
+        self.remove_all(&mut removed);
     }
 }
 
-impl AgentBase<SystemRequest> for Agent {
-    fn empty_system_outbox(&mut self) -> Vec<SystemRequest> {
-        match self {
-            Agent::Creator(state) => state.system_outbox.drain(..).collect(),
-            _ => vec![],
-        }
+fn print_agents(context: &Context) {
+    for agent in context.agents.values() {
+        println!("  {:?}", agent)
     }
 }
 
-fn print_agents(context: &Context) {
-    for (_agent_id, agent) in &context.agents {
-        println!("  {:?}", agent)
+impl IdContext for Context {
+    fn ids(&self) -> &IdAllocator {
+        &self.ids
+    }
+    fn ids_mut(&mut self) -> &mut IdAllocator {
+        &mut self.ids
     }
 }
 
@@ -347,6 +441,10 @@ impl MapContext<Agent> for Context {
     fn agents(&self) -> &BTreeMap<AgentId, Agent> {
         &self.agents
     }
+
+    fn agents_mut(&mut self) -> &mut BTreeMap<AgentId, Agent> {
+        &mut self.agents
+    }
 }
 
 #[test]
@@ -377,12 +475,117 @@ fn test_create_and_remove_mapped() {
     let _m: &dyn Behaviour<Agent, Context> = &mapped;
 }
 
+#[test]
+fn test_create_and_remove_mapped_ref() {
+    let single_creator_behaviour = Chain::chain(FlockCreator {}, RemoveSelfBehaviour {});
+    let mapped = act_map_ref(
+        Agent::as_creator,
+        |_: &Agent, creator| Agent::Creator(creator),
+        single_creator_behaviour,
+    );
+    let _m: &dyn Behaviour<Agent, Context> = &mapped;
+}
+
 #[test]
 fn test_flock_behaviuor() {
     let flock_behaviour = FlockBehaviour {};
     let _m: &dyn Behaviour<Boid, Context> = &flock_behaviour;
 }
 
+#[test]
+fn test_parallel_steering() {
+    // Both branches see the same original boid, and their directions are averaged.
+    let steering = parallel(
+        (FlockBehaviour {}, FlockBehaviour {}),
+        weighted_merge(
+            [0.5, 0.5],
+            |boid: &Boid| boid.direction,
+            |boid: &mut Boid, direction| boid.direction = direction,
+        ),
+    );
+    let _m: &dyn Behaviour<Boid, Context> = &steering;
+}
+
+#[test]
+fn test_checked_flock_removes_degenerate_boids() {
+    let behaviour = try_act_map_if(
+        |agent: Agent| agent.try_into_boid(),
+        CheckedFlockBehaviour {},
+    );
+
+    let mut context = Context::new();
+    let degenerate = context.create_agent(|id| {
+        Agent::Boid(Boid {
+            id,
+            position: Vector3::new(0.0, 0.0, 0.0),
+            direction: Vector3::new(0.0, 0.0, 0.0),
+            rgb: (1, 2, 3),
+        })
+    });
+    let healthy = context.create_agent(|id| {
+        Agent::Boid(Boid {
+            id,
+            position: Vector3::new(100.0, 0.0, 0.0),
+            direction: Vector3::new(1.0, 0.0, 0.0),
+            rgb: (1, 2, 3),
+        })
+    });
+
+    let aborted = try_step_agents(&behaviour, &mut context, ErrorPolicy::AbortStep);
+    assert_eq!(
+        aborted,
+        Err(vec![AgentError {
+            id: degenerate,
+            error: FlockError::DegenerateDirection(degenerate),
+        }])
+    );
+    assert_eq!(context.agents.len(), 2);
+
+    let errors = try_step_agents(&behaviour, &mut context, ErrorPolicy::KeepPrevious).unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(context.agents.len(), 2);
+
+    remove_failed_agents::<_, SystemRequest, _>(&mut context, &errors);
+    assert_eq!(context.agents.keys().collect::<Vec<_>>(), vec![&healthy]);
+    assert_eq!(context.ids().status(degenerate), IdStatus::Stale);
+}
+
+/// A larger context that only contains a flocking context.
+/// It does not implement FlockGlobalContext or NeighborhoodContext itself.
+struct WorldContext {
+    flock: Context,
+    _wind: Vector3<f32>,
+}
+
+#[test]
+fn test_flock_behaviour_in_larger_context() {
+    let flock = with_mapped_context(
+        |world: &WorldContext| &world.flock,
+        act_map_ref(
+            Agent::as_boid,
+            |_: &Agent, boid| Agent::Boid(boid),
+            FlockBehaviour {},
+        ),
+    );
+    let _m: &dyn Behaviour<Agent, WorldContext> = &flock;
+
+    let world = WorldContext {
+        flock: Context::new(),
+        _wind: Vector3::new(1.0, 0.0, 0.0),
+    };
+    let state = Agent::Boid(Boid {
+        id: AgentId(1),
+        position: Vector3::new(0.0, 0.0, 0.0),
+        direction: Vector3::new(1.0, 0.0, 0.0),
+        rgb: (1, 2, 3),
+    });
+    let new_state = flock.act(&state, &world);
+    assert_eq!(
+        new_state.as_boid().unwrap().position,
+        Vector3::new(1.0, 0.0, 0.0)
+    );
+}
+
 #[test]
 fn test_main() {
     let single_creator_behaviour = Chain::chain(FlockCreator {}, RemoveSelfBehaviour {});
@@ -404,55 +607,61 @@ fn test_main() {
             preferred_flock_size: 4.0,
         },
         agents: BTreeMap::new(),
+        ids: IdAllocator::monotonic(),
         search_radius: 10.0,
     };
 
-    let mut state = Agent::Creator(Creator {
+    let state = Agent::Creator(Creator {
         id: AgentId(0),
         system_outbox: vec![],
     });
-    create_or_flock.act(&mut state, &context);
+    create_or_flock.act(&state, &context);
 
-    let mut state = Agent::Boid(Boid {
+    let state = Agent::Boid(Boid {
         id: AgentId(1),
         position: Vector3::new(0.0, 0.0, 0.0),
         direction: Vector3::new(0.0, 0.0, 0.0),
         rgb: (1, 2, 3),
     });
-    create_or_flock.act(&mut state, &context);
+    create_or_flock.act(&state, &context);
 }
 
 #[test]
 fn test_full_example() {
-    // Create a composite behaviour which creates the flock then removes itself
-    let single_creator_behaviour = Chain::chain(FlockCreator {}, RemoveSelfBehaviour {});
-
     // The base behaviour which behaves differently if the agent is a Creator or a Boid.
-    let create_or_flock = Chain::chain(
-        act_map_if(
-            |agent: Agent| agent.try_into_creator(),
-            single_creator_behaviour,
-        ),
-        act_map_if(|agent: Agent| agent.try_into_boid(), FlockBehaviour {}),
-    );
+    // A creator creates the flock then removes itself.
+    // Agents that don't match a branch are passed through without being cloned.
+    let create_or_flock = FlockCreator {}
+        .then(RemoveSelfBehaviour {})
+        .map_ref(Agent::as_creator, |_: &Agent, creator| {
+            Agent::Creator(creator)
+        })
+        .then(FlockBehaviour {}.map_ref(Agent::as_boid, |_: &Agent, boid| Agent::Boid(boid)));
 
     // Initial context with just a single creator object.
     let mut context = Context::new();
-    context.agents.insert(
-        AgentId(0),
+    let creator = context.create_agent(|id| {
         Agent::Creator(Creator {
-            id: AgentId(0),
+            id,
             system_outbox: vec![],
-        }),
-    );
+        })
+    });
 
+    let mut simulation: Simulation<_, _, _, SystemRequest> =
+        Simulation::new(create_or_flock, context);
     for i in 0..10 {
         println!("Step {}", i);
-        print_agents(&context);
-        step_agents(&create_or_flock, &mut context);
-
-        // let messages = gather_messages(&mut context);
-        perform_system_actions(&mut context);
-        // deliver_messages(&mut context, messages);
+        print_agents(simulation.context());
+        simulation.step();
     }
+    assert_eq!(simulation.steps(), 10);
+
+    // The creator removed itself, and its id is never handed out again.
+    let ids = simulation.context().ids();
+    assert_eq!(ids.status(creator), IdStatus::Stale);
+    assert!(simulation
+        .context()
+        .agents
+        .keys()
+        .all(|id| ids.status(*id) == IdStatus::Live));
 }
@@ -1,30 +1,29 @@
 extern crate rust_agents;
 
 use std::collections::BTreeMap;
+use std::convert::Infallible;
 
 use rust_agents::behaviour::Behaviour;
-use rust_agents::utils::{
-    map_tree_leaves, perform_system_actions, AgentBase, AgentId, BaseOp, System, SystemOp,
-};
+use rust_agents::from_fn::from_fn;
+use rust_agents::ids::{IdAllocator, IdContext, IdStatus};
+use rust_agents::map_context::MapContext;
+use rust_agents::names::{NameRegistry, NamedContext};
+use rust_agents::requests::{CreateAgent, RemoveAgent, Request, RequestHandler};
+use rust_agents::simulation::Simulation;
+use rust_agents::utils::{AgentBase, AgentId, BaseOp, SetIdOp, SystemOp};
 
 trait ChildGenOp {
     type RequestType;
     fn child_requests(&self) -> Vec<Self::RequestType>;
 }
 
-#[derive(Debug, Clone)]
-struct RemoveAgentRequest {
-    from: AgentId,
-    remove: AgentId,
-}
-
 struct CreatorBehaviour {}
 
 impl<STATE, CONTEXT, REQUEST> Behaviour<STATE, CONTEXT> for CreatorBehaviour
 where
     STATE: BaseOp + ChildGenOp<RequestType = REQUEST> + SystemOp<RequestType = REQUEST> + Clone,
-    CONTEXT: ,
-    REQUEST: From<RemoveAgentRequest>,
+    CONTEXT:,
+    REQUEST: From<RemoveAgent>,
 {
     fn act(&self, state: &STATE, _context: &CONTEXT) -> STATE {
         let mut new_state = state.clone();
@@ -32,31 +31,14 @@ where
             //This should really be child.into()
             new_state.request(child);
         }
-        let request = RemoveAgentRequest {
-            from: new_state.id(),
-            remove: new_state.id(),
-        };
+        let request = RemoveAgent(new_state.id());
         new_state.request(request.into());
         new_state
     }
 }
 
-#[derive(Debug, Clone)]
-struct CreateAgentRequest {
-    new_id: AgentId,
-}
-
-#[derive(Debug, Clone)]
-enum SystemRequest {
-    RemoveAgent(RemoveAgentRequest),
-    CreateAgent(CreateAgentRequest),
-}
-
-impl From<RemoveAgentRequest> for SystemRequest {
-    fn from(v: RemoveAgentRequest) -> Self {
-        SystemRequest::RemoveAgent(v)
-    }
-}
+// The library requests cover everything this simulation needs.
+type SystemRequest = Request<Agent>;
 
 #[derive(Debug, Clone)]
 struct CreatorState {
@@ -67,10 +49,9 @@ struct CreatorState {
 impl ChildGenOp for CreatorState {
     type RequestType = SystemRequest;
     fn child_requests(&self) -> Vec<Self::RequestType> {
-        vec![
-            SystemRequest::CreateAgent(CreateAgentRequest { new_id: AgentId(7) }),
-            SystemRequest::CreateAgent(CreateAgentRequest { new_id: AgentId(8) }),
-        ]
+        // The context gives each child its own id when it is inserted.
+        let child = CreateAgent::new(Agent::Child(ChildState { id: self.id }));
+        vec![child.clone().into(), child.into()]
     }
 }
 
@@ -110,18 +91,20 @@ impl From<ChildState> for Agent {
     }
 }
 
-impl Agent {
-    pub fn id(&self) -> AgentId {
+impl SetIdOp for Agent {
+    fn set_id(&mut self, id: AgentId) {
         match self {
-            Agent::Creator(state) => state.id,
-            Agent::Child(state) => state.id,
+            Agent::Creator(state) => state.id = id,
+            Agent::Child(state) => state.id = id,
         }
     }
+}
 
-    pub fn act<CONTEXT>(&self, context: &CONTEXT) -> Self {
+impl BaseOp for Agent {
+    fn id(&self) -> AgentId {
         match self {
-            Agent::Creator(state) => (CreatorBehaviour {}).act(state, context).into(),
-            Agent::Child(state) => state.clone().into(), //No child behaviour yet.
+            Agent::Creator(state) => state.id,
+            Agent::Child(state) => state.id,
         }
     }
 }
@@ -135,80 +118,83 @@ impl AgentBase<SystemRequest> for Agent {
     }
 }
 
+impl Agent {
+    pub fn act<CONTEXT>(&self, context: &CONTEXT) -> Self {
+        match self {
+            Agent::Creator(state) => (CreatorBehaviour {}).act(state, context).into(),
+            Agent::Child(state) => state.clone().into(), //No child behaviour yet.
+        }
+    }
+}
+
 struct GlobalContext {
     agents: BTreeMap<AgentId, Agent>,
+    ids: IdAllocator,
+    names: NameRegistry,
 }
 
 impl GlobalContext {
     pub fn new() -> Self {
         GlobalContext {
             agents: BTreeMap::new(),
+            ids: IdAllocator::generational(),
+            names: NameRegistry::new(),
         }
     }
 }
 
 fn print_agents(context: &GlobalContext) {
-    for (_agent_id, agent) in &context.agents {
+    for agent in context.agents.values() {
         println!("  {:?}", agent)
     }
 }
 
-fn step_agents(context: &mut GlobalContext) {
-    context.agents = map_tree_leaves(&context.agents, |agent| agent.act(context));
-}
-
-// fn gather_messages(context: &mut GlobalContext) -> Vec<Message> {
-//     let mut messages = vec![];
-//     for (_agent_id, agent) in &mut context.agents {
-//         messages.append(&mut agent.state.outbox);
-//     }
-//     messages
-// }
-
-impl System<SystemRequest> for GlobalContext {
-    type AgentType = Agent;
-
-    fn apply_system_request(&mut self, action: SystemRequest) {
-        match action {
-            SystemRequest::RemoveAgent(request) => {
-                println!(
-                    "{:?} requested removal of {:?}",
-                    request.from, request.remove
-                );
-                let _agent = self.agents.remove(&request.remove).unwrap();
-            }
-            SystemRequest::CreateAgent(request) => {
-                println!("Creation request {:?}", request);
-                self.agents.insert(
-                    request.new_id,
-                    Agent::Child(ChildState { id: request.new_id }),
-                );
-            }
-        }
+impl MapContext<Agent> for GlobalContext {
+    fn agents(&self) -> &BTreeMap<AgentId, Agent> {
+        &self.agents
+    }
+
+    fn agents_mut(&mut self) -> &mut BTreeMap<AgentId, Agent> {
+        &mut self.agents
+    }
+}
+
+impl IdContext for GlobalContext {
+    fn ids(&self) -> &IdAllocator {
+        &self.ids
     }
+    fn ids_mut(&mut self) -> &mut IdAllocator {
+        &mut self.ids
+    }
+}
 
-    fn agents_mut(&mut self) -> Vec<&mut Agent> {
-        return self
-            .agents
-            .iter_mut()
-            .map(|(_agent_id, agent)| agent)
-            .collect();
+// The agents have no names, but the standard requests keep the registry.
+impl NamedContext for GlobalContext {
+    fn names(&self) -> &NameRegistry {
+        &self.names
+    }
+    fn names_mut(&mut self) -> &mut NameRegistry {
+        &mut self.names
     }
 }
 
-// fn deliver_messages(context:&mut GlobalContext, messages:Vec<Message>) {
-//     for (_agent_id, agent) in &mut context.agents {
-//         agent.state.inbox.clear();
-//     }
-//
-//     for message in messages {
-//         println!("MESSAGE: {:?}", message);
-//         match context.agents.get_mut(&message.to) {
-//             Some(agent) => { agent.state.inbox.push(message); },
-//             None => { println!("No agent {:?}", message.to); }
-//         }
-//     }
-// }
+impl RequestHandler<Agent, Infallible> for GlobalContext {
+    fn apply_custom_request(&mut self, request: Infallible) {
+        match request {}
+    }
+
+    fn apply_modification(&mut self, _id: AgentId, modification: Infallible) {
+        match modification {}
+    }
+
+    fn agent_created(&mut self, id: AgentId) {
+        println!("Created {:?}", id);
+    }
+
+    fn agent_removed(&mut self, id: AgentId, _agent: Agent) {
+        println!("Removed {:?}", id);
+    }
+}
 
 #[test]
 fn test_use_creator_agent_pattern_no_loop() {
@@ -228,27 +214,30 @@ fn test_use_creator_agent_pattern_no_loop() {
 
 #[test]
 fn test_use_creator_agent_pattern() {
-    let initial_state: Vec<Agent> = vec![Agent::Creator(CreatorState {
-        id: AgentId(1),
-        system_outbox: vec![],
-    })];
-
-    let mut agents: BTreeMap<AgentId, Agent> = BTreeMap::new();
-
-    for a in initial_state {
-        agents.insert(a.id(), a);
-    }
-
     let mut context = GlobalContext::new();
-    context.agents = agents;
+    let creator = context.create_agent(|id| {
+        Agent::Creator(CreatorState {
+            id,
+            system_outbox: vec![],
+        })
+    });
+
+    let behaviour = from_fn(|agent: &Agent, context: &GlobalContext| agent.act(context));
+    let simulation: Simulation<_, _, _, SystemRequest> = Simulation::new(behaviour, context);
+    // Remove the creator before creating its children, so the first child
+    // reuses its slot.
+    let mut simulation = simulation.with_request_order(SystemRequest::phase);
 
     for i in 0..10 {
         println!("Step {}", i);
-        print_agents(&context);
-        step_agents(&mut context);
-
-        // let messages = gather_messages(&mut context);
-        perform_system_actions(&mut context);
-        // deliver_messages(&mut context, messages);
+        print_agents(simulation.context());
+        simulation.step();
     }
+
+    // The creator replaced itself with its children, and its id is stale.
+    let context = simulation.context();
+    let ids: Vec<AgentId> = context.agents.keys().cloned().collect();
+    assert_eq!(ids, vec![AgentId(1), AgentId(1 << 32)]);
+    assert_eq!(creator, AgentId(0));
+    assert_eq!(context.ids.status(creator), IdStatus::Stale);
 }
@@ -0,0 +1,149 @@
+#![cfg(feature = "derive")]
+/// Tests for the derive macros re-exported by rust_agents.
+use rust_agents::act_map_if::TryIntoResult;
+use rust_agents::behaviour::Behaviour;
+use rust_agents::behaviour_ext::BehaviourExt;
+use rust_agents::utils::{AgentBase, AgentEnum, AgentId, BaseOp, Color, ColorOp, SystemOp};
+
+#[derive(Debug, Clone, PartialEq)]
+struct Counter {
+    count: u32,
+}
+
+struct Increment {}
+
+impl<CONTEXT> Behaviour<Counter, CONTEXT> for Increment {
+    fn act(&self, state: &Counter, _context: &CONTEXT) -> Counter {
+        Counter {
+            count: state.count + 1,
+        }
+    }
+}
+
+struct AddContext {}
+
+impl Behaviour<Counter, u32> for AddContext {
+    fn act(&self, state: &Counter, context: &u32) -> Counter {
+        Counter {
+            count: state.count + context,
+        }
+    }
+}
+
+/// Bounds are inferred from the variants, so this is only a
+/// Behaviour<Counter, u32> since AddContext needs a u32 context.
+#[derive(Behaviour)]
+enum InferredBehaviour {
+    Increment(Increment),
+    Add(AddContext),
+}
+
+/// Increment works for any context, so here we pick one explicitly.
+#[derive(Behaviour)]
+#[behaviour(state = Counter, context = ())]
+enum ExplicitBehaviour {
+    Increment(Increment),
+}
+
+/// The enum can have its own generic parameters.
+#[derive(Behaviour)]
+enum GenericBehaviour<B> {
+    Inner(B),
+    Increment(Increment),
+}
+
+#[test]
+fn test_derive_behaviour_inferred() {
+    let _m: &dyn Behaviour<Counter, u32> = &InferredBehaviour::Increment(Increment {});
+
+    let state = Counter { count: 1 };
+    let b = InferredBehaviour::Add(AddContext {});
+    assert_eq!(b.act(&state, &10), Counter { count: 11 });
+    let b = InferredBehaviour::Increment(Increment {});
+    assert_eq!(b.act(&state, &10), Counter { count: 2 });
+}
+
+#[test]
+fn test_derive_behaviour_explicit() {
+    let b = ExplicitBehaviour::Increment(Increment {});
+    let _m: &dyn Behaviour<Counter, ()> = &b;
+    assert_eq!(b.act(&Counter { count: 1 }, &()), Counter { count: 2 });
+}
+
+#[test]
+fn test_derive_behaviour_generic() {
+    let b: GenericBehaviour<AddContext> = GenericBehaviour::Inner(AddContext {});
+    assert_eq!(b.act(&Counter { count: 1 }, &2), Counter { count: 3 });
+    let b: GenericBehaviour<AddContext> = GenericBehaviour::Increment(Increment {});
+    assert_eq!(b.act(&Counter { count: 1 }, &2), Counter { count: 2 });
+}
+
+#[test]
+fn test_derive_behaviour_ext() {
+    // The derive also implements BehaviourExt.
+    let b = ExplicitBehaviour::Increment(Increment {}).then(Increment {});
+    assert_eq!(b.act(&Counter { count: 1 }, &()), Counter { count: 3 });
+}
+
+#[derive(Debug, Clone, PartialEq, BaseOp)]
+struct Walker {
+    #[agent(id)]
+    id: AgentId,
+    steps: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, BaseOp, SystemOp, AgentBase)]
+struct Spawner {
+    #[agent(id)]
+    id: AgentId,
+    #[agent(system_outbox)]
+    outbox: Vec<u32>,
+}
+
+/// The capability derives also work on tuple structs.
+#[derive(Debug, Clone, BaseOp, ColorOp)]
+struct Marker(#[agent(id)] AgentId, #[agent(color)] Color);
+
+#[derive(Debug, Clone, PartialEq, AgentEnum)]
+#[agent(request = u32)]
+enum SimAgent {
+    #[agent(no_outbox)]
+    Walker(Walker),
+    Spawner(Spawner),
+}
+
+#[test]
+fn test_derive_agent_enum() {
+    let walker = Walker {
+        id: AgentId(1),
+        steps: 0,
+    };
+    let mut agent: SimAgent = walker.clone().into();
+    assert_eq!(agent.id(), AgentId(1));
+    assert_eq!(agent.as_walker(), Some(&walker));
+    assert_eq!(agent.as_spawner(), None);
+
+    agent.as_walker_mut().unwrap().steps += 1;
+    assert_eq!(agent.empty_system_outbox(), Vec::<u32>::new());
+    match agent.try_into_spawner() {
+        TryIntoResult::Ok(_) => panic!("walker converted to spawner"),
+        TryIntoResult::Failed(agent) => assert_eq!(agent.as_walker().unwrap().steps, 1),
+    }
+
+    let mut spawner = Spawner {
+        id: AgentId(2),
+        outbox: vec![],
+    };
+    spawner.request(7);
+    let mut agent = SimAgent::from(spawner);
+    assert_eq!(agent.id(), AgentId(2));
+    assert_eq!(agent.empty_system_outbox(), vec![7]);
+}
+
+#[test]
+fn test_derive_capabilities() {
+    let mut marker = Marker(AgentId(3), Color::Black);
+    assert_eq!(marker.id(), AgentId(3));
+    marker.set_color(Color::Red);
+    assert!(matches!(marker.get_color(), Color::Red));
+}
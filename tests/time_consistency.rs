@@ -3,10 +3,12 @@
 /// its time with its neighbours, ensuring that they are consistent.
 use rust_agents::{
     behaviour::Behaviour,
+    behaviour_mut::BehaviourMut,
     map_context::SimpleMapContext,
-    utils::{step_agents, AgentId},
+    utils::{step_agents, step_agents_mut, AgentId},
 };
 
+#[allow(dead_code)]
 #[derive(Clone)]
 struct TimeCheckAgent {
     id: AgentId,
@@ -48,17 +50,21 @@ impl Behaviour<TimeCheckAgent, TimeCheckContext> for TimeCheckBehaviour {
     }
 }
 
-#[test]
-fn test_full_example() {
-    let mut context = TimeCheckContext::new();
-    context.agents = (0..10)
-        .map(|i| (AgentId(i), TimeCheckAgent::new(i)))
-        .collect();
+/// The same check written as an in-place behaviour.
+/// step_agents_mut must still show every agent the previous time-step.
+impl BehaviourMut<TimeCheckAgent, TimeCheckContext> for TimeCheckBehaviour {
+    fn act_mut(&self, state: &mut TimeCheckAgent, context: &TimeCheckContext) {
+        let all_match = context
+            .agents
+            .values()
+            .all(|n| n.current_time == state.current_time);
 
-    for _i in 0..10 {
-        step_agents(&TimeCheckBehaviour, &mut context);
+        state.current_time += 1;
+        state.detected_bad_time = state.detected_bad_time || !all_match;
     }
+}
 
+fn check_agents(context: TimeCheckContext) {
     for (id, agent) in context.agents {
         assert!(
             !agent.detected_bad_time,
@@ -72,3 +78,31 @@ fn test_full_example() {
         );
     }
 }
+
+#[test]
+fn test_full_example() {
+    let mut context = TimeCheckContext::new();
+    context.agents = (0..10)
+        .map(|i| (AgentId(i), TimeCheckAgent::new(i)))
+        .collect();
+
+    for _i in 0..10 {
+        step_agents(&TimeCheckBehaviour, &mut context);
+    }
+
+    check_agents(context);
+}
+
+#[test]
+fn test_full_example_mut() {
+    let mut context = TimeCheckContext::new();
+    context.agents = (0..10)
+        .map(|i| (AgentId(i), TimeCheckAgent::new(i)))
+        .collect();
+
+    for _i in 0..10 {
+        step_agents_mut(&TimeCheckBehaviour, &mut context);
+    }
+
+    check_agents(context);
+}